@@ -0,0 +1,454 @@
+//! Programs as text.
+
+use std::collections::HashMap;
+
+use crate::format::{friendly_hex_u32, FormatStyle};
+use crate::link::Module;
+use crate::{
+    Inherent, Insn, ItemId, ObjectFile, JUMP_IF_ZERO, JUMP_RELATIVE,
+    JUMP_TARGET_MASK,
+};
+
+/// Why `assemble` rejected a line.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AssembleError {
+    /// Counting from 1.
+    pub line: usize,
+    pub msg: &'static str,
+}
+
+/// Assemble one instruction per line, in the syntax `disassemble` prints:
+/// a mnemonic and an immediate in decimal, `0x` hex, or `#` hex, with `_`
+/// allowed between digits. `inh` takes a name or a number. `jump` and
+/// `jumpz` take a `#` address, or a signed byte offset like `+8` for a
+/// relative jump; either has to be a multiple of 4. `ldi` loads any
+/// 32-bit value into X; it's shorthand for the two instructions from
+/// `Insn::load_u32`. Everything after `;` is a comment, and blank lines
+/// are skipped. A line may start with a `#` address, as in `disassemble`'s
+/// output, but then it has to be the address the instruction ends up at.
+///
+/// A line can also start with a label, like `loop:`, naming the address
+/// of the next instruction. `jump`, `jumpz`, and `xlo` take a label in
+/// place of an address. Labels are made of letters, digits, and `_`, and
+/// don't start with a digit.
+///
+/// `.entry label` says where the program starts. Only `assemble_object`
+/// uses it; the code from `assemble` starts at address 0, as always.
+/// `.global` and `.extern` are for `assemble_module`; a label declared
+/// `.extern` can't be used here, since nothing would fill it in. Item
+/// names are only for `assemble_with_names`, which hands back their table.
+pub fn assemble(src: &str) -> Result<Vec<u32>, AssembleError> {
+    Ok(assemble_unit(src)?.linked()?.unnamed()?.code)
+}
+
+/// Names for item ids, so dumps can say `counter` instead of `#0000_0001`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ItemNames(HashMap<ItemId, String>);
+
+impl ItemNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, id: ItemId, name: &str) {
+        self.0.insert(id, name.to_string());
+    }
+
+    pub fn get(&self, id: ItemId) -> Option<&str> {
+        self.0.get(&id).map(String::as_str)
+    }
+
+    /// The id called `name`, if any.
+    pub fn id(&self, name: &str) -> Option<ItemId> {
+        self.0.iter().find(|(_, n)| *n == name).map(|(&id, _)| id)
+    }
+
+    /// The name of `id`, or the id itself in `style` if it hasn't got one.
+    pub fn describe(&self, id: ItemId, style: FormatStyle) -> String {
+        match self.get(id) {
+            Some(name) => name.to_string(),
+            None => style.id(id),
+        }
+    }
+}
+
+/// Like `assemble`, but `def`, `set`, `push`, and `val` can also take a
+/// name, made of letters, digits, and `_` and not starting with a digit.
+/// Each name gets the lowest nonzero id that isn't written as a number
+/// anywhere in `src`, in order of first use, and the table of them comes
+/// back with the code.
+pub fn assemble_with_names(src: &str)
+    -> Result<(Vec<u32>, ItemNames), AssembleError>
+{
+    let unit = assemble_unit(src)?.linked()?;
+    Ok((unit.code, unit.names))
+}
+
+/// Like `assemble`, but as an object file: its entry point comes from
+/// `.entry`, or is 0 without one, and every word holding a label's address
+/// is listed as a relocation, so it can be loaded anywhere. The labels go
+/// in its symbol table, and each word's source line in its source map.
+pub fn assemble_object(src: &str) -> Result<ObjectFile, AssembleError> {
+    let unit = assemble_unit(src)?.linked()?.unnamed()?;
+    let mut symbols: Vec<_> = unit.labels.into_iter()
+        .map(|(label, addr)| (label.to_string(), addr))
+        .collect();
+    symbols.sort_by_key(|&(_, addr)| addr);
+    Ok(ObjectFile {
+        entry: unit.entry,
+        code: unit.code,
+        relocs: unit.relocs,
+        symbols,
+        lines: unit.lines,
+    })
+}
+
+/// Like `assemble`, but as a module for `link::link`. `.global label`
+/// exports a label to the other modules, and `.extern name` lets `name`
+/// be used like a label here when another module exports it; the linker
+/// fills in its address.
+pub fn assemble_module(src: &str) -> Result<Module, AssembleError> {
+    let unit = assemble_unit(src)?.unnamed()?;
+    let code = unit.code.iter().map(|&w| Insn::from_u32(w)).collect();
+    let externs = unit.externs.into_iter()
+        .map(|(_, index, name)| (index, name.to_string()))
+        .collect();
+    Ok(Module { code, relocs: unit.relocs, globals: unit.globals, externs })
+}
+
+/// Everything assembling a source file produces. Each public function
+/// hands back the parts its callers need.
+struct Unit<'a> {
+    code: Vec<u32>,
+    names: ItemNames,
+    /// The first line that uses an item name.
+    named_at: Option<usize>,
+    entry: u32,
+    /// Indices of words whose immediates are label addresses.
+    relocs: Vec<u32>,
+    /// Exported labels, as instruction indices.
+    globals: Vec<(String, u32)>,
+    /// Words that need an extern's address: the line, the index, and the
+    /// name.
+    externs: Vec<(usize, u32, &'a str)>,
+    /// Every label and its address.
+    labels: HashMap<&'a str, u32>,
+    /// The source line each word came from.
+    lines: Vec<u32>,
+}
+
+impl<'a> Unit<'a> {
+    /// The unit, if it can run without linking.
+    fn linked(self) -> Result<Self, AssembleError> {
+        match self.externs.first() {
+            Some(&(line, ..)) => Err(AssembleError {
+                line, msg: "extern label needs `assemble_module`",
+            }),
+            None => Ok(self),
+        }
+    }
+
+    /// The unit, if it doesn't need its item names kept.
+    fn unnamed(self) -> Result<Self, AssembleError> {
+        match self.named_at {
+            Some(line) => Err(AssembleError {
+                line, msg: "item name needs `assemble_with_names`",
+            }),
+            None => Ok(self),
+        }
+    }
+}
+
+/// Builds an instruction from its immediate, like `Insn::Def`.
+type MakeInsn = fn(u32) -> Insn;
+
+/// A label used as an operand, to fill in once every label is known.
+struct LabelUse<'a> {
+    index: usize,
+    line: usize,
+    label: &'a str,
+    make: MakeInsn,
+    /// The bits the address has to fit in.
+    mask: u32,
+}
+
+fn assemble_unit(src: &str) -> Result<Unit<'_>, AssembleError> {
+    let mut code = Vec::new();
+    // Where each item name is used, and what to build there once it has
+    // an id.
+    let mut uses = Vec::new();
+    let mut named_at = None;
+    let mut numbered = Vec::new();
+    let mut labels = HashMap::new();
+    let mut label_uses = Vec::new();
+    let mut entry = None;
+    let mut global_names = Vec::new();
+    let mut extern_names = Vec::new();
+    let mut lines = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        // Whatever the previous line emitted came from line `i`.
+        lines.resize(code.len(), i as u32);
+        let err = |msg| AssembleError { line: i + 1, msg };
+        let line = line.split(';').next().unwrap();
+        let mut words = line.split_whitespace().peekable();
+        if let Some(addr) = words.next_if(|w| w.starts_with('#')) {
+            if number(addr) != Some(4*code.len() as u32) {
+                return Err(err("address doesn't match the code offset"));
+            }
+        }
+        if let Some(label) = words.next_if(|w| w.ends_with(':')) {
+            let label = &label[..label.len() - 1];
+            if !is_name(label) {
+                return Err(err("label isn't a name"));
+            }
+            if labels.insert(label, 4*code.len() as u32).is_some() {
+                return Err(err("label is already defined"));
+            }
+            if words.peek().is_some_and(|w| w.ends_with(':')) {
+                return Err(err("line has more than one label"));
+            }
+        }
+        let op = match words.next() {
+            Some(op) => op,
+            None => continue,
+        };
+        let arg = words.next().ok_or_else(|| err("missing operand"))?;
+        match words.next() {
+            Some(w) if w.ends_with(':') => {
+                return Err(err("label isn't at the start of the line"));
+            },
+            Some(_) => return Err(err("too many operands")),
+            None => (),
+        }
+        if op == ".entry" {
+            if entry.is_some() {
+                return Err(err("entry is already given"));
+            }
+            entry = Some((i + 1, arg));
+            continue;
+        }
+        if op == ".global" || op == ".extern" {
+            if !is_name(arg) {
+                return Err(err("label isn't a name"));
+            }
+            if op == ".global" {
+                global_names.push((i + 1, arg));
+            } else if !extern_names.contains(&arg) {
+                extern_names.push(arg);
+            }
+            continue;
+        }
+        if op == "ldi" {
+            let value = number(arg)
+                .ok_or_else(|| err("immediate isn't a number or is too big"))?;
+            code.extend(Insn::load_u32(value).iter().map(Insn::as_u32));
+            continue;
+        }
+        let by_id: Option<MakeInsn> = match op {
+            "def" => Some(Insn::Def),
+            "set" => Some(Insn::Set),
+            "push" => Some(Insn::Push),
+            "val" => Some(Insn::Val),
+            _ => None,
+        };
+        if let Some(make) = by_id {
+            if is_name(arg) {
+                named_at.get_or_insert(i + 1);
+                uses.push((code.len(), arg, make));
+                code.push(0);
+                continue;
+            }
+            numbered.extend(number(arg));
+        }
+        // What to build from a label's address, and the bits it has to fit
+        // in.
+        let by_label: Option<(MakeInsn, u32)> = match op {
+            "jump" => Some((Insn::Jump, JUMP_TARGET_MASK)),
+            "jumpz" => Some((jump_if_zero, JUMP_TARGET_MASK)),
+            "xlo" => Some((Insn::Xlo, 0x1FFF_FFFF)),
+            _ => None,
+        };
+        if let Some((make, mask)) = by_label {
+            if is_name(arg) {
+                let index = code.len();
+                label_uses.push(LabelUse {
+                    index, line: i + 1, label: arg, make, mask,
+                });
+                code.push(0);
+                continue;
+            }
+        }
+        let insn = insn(op, arg).map_err(err)?;
+        insn.validate().map_err(err)?;
+        code.push(insn.as_u32());
+    }
+
+    lines.resize(code.len(), src.lines().count() as u32);
+
+    let mut names = ItemNames::new();
+    let mut next = 1;
+    for (i, name, make) in uses {
+        let id = match names.id(name) {
+            Some(id) => id,
+            None => {
+                while numbered.contains(&next) {
+                    next += 1;
+                }
+                let id = ItemId::new(next).unwrap();
+                names.insert(id, name);
+                next += 1;
+                id
+            },
+        };
+        code[i] = make(id.get()).as_u32();
+    }
+
+    let mut relocs = Vec::with_capacity(label_uses.len());
+    let mut externs = Vec::new();
+    for u in label_uses {
+        let err = |msg| AssembleError { line: u.line, msg };
+        if extern_names.contains(&u.label) {
+            if labels.contains_key(u.label) {
+                return Err(err("label is both extern and defined here"));
+            }
+            code[u.index] = (u.make)(0).as_u32();
+            externs.push((u.line, u.index as u32, u.label));
+            continue;
+        }
+        let addr = *labels.get(u.label)
+            .ok_or_else(|| err("label isn't defined"))?;
+        if addr & u.mask != addr {
+            return Err(err("label's address doesn't fit in the immediate"));
+        }
+        code[u.index] = (u.make)(addr).as_u32();
+        relocs.push(u.index as u32);
+    }
+
+    let entry = match entry {
+        None => 0,
+        Some((line, label)) => {
+            let err = |msg| AssembleError { line, msg };
+            let addr = *labels.get(label)
+                .ok_or_else(|| err("label isn't defined"))?;
+            if addr as usize >= 4*code.len() {
+                return Err(err("entry label isn't at an instruction"));
+            }
+            addr
+        },
+    };
+    let mut globals = Vec::with_capacity(global_names.len());
+    for (line, label) in global_names {
+        let err = |msg| AssembleError { line, msg };
+        let addr = *labels.get(label)
+            .ok_or_else(|| err("label isn't defined"))?;
+        if addr as usize >= 4*code.len() {
+            return Err(err("global label isn't at an instruction"));
+        }
+        globals.push((label.to_string(), addr / 4));
+    }
+    Ok(Unit {
+        code, names, named_at, entry, relocs, globals, externs, labels, lines,
+    })
+}
+
+fn jump_if_zero(target: u32) -> Insn {
+    Insn::Jump(JUMP_IF_ZERO | target)
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn insn(op: &str, arg: &str) -> Result<Insn, &'static str> {
+    let imm = || number(arg).ok_or("immediate isn't a number or is too big");
+    Ok(match op {
+        "def" => Insn::Def(imm()?),
+        "set" => Insn::Set(imm()?),
+        "push" => Insn::Push(imm()?),
+        "inh" => Insn::Inh(match arg {
+            "call" => Inherent::Call,
+            "pop" => Inherent::Pop,
+            "alloc" => Inherent::Alloc,
+            "frame" => Inherent::Frame,
+            "pc" => Inherent::Pc,
+            "add" => Inherent::Add,
+            "sub" => Inherent::Sub,
+            "mul" => Inherent::Mul,
+            "cmp" => Inherent::Cmp,
+            _ => Inherent::from_u32(imm()?),
+        }),
+        "jump" => Insn::Jump(jump_target(arg)?),
+        "jumpz" => Insn::Jump(JUMP_IF_ZERO | jump_target(arg)?),
+        "val" => Insn::Val(imm()?),
+        "xlo" => Insn::Xlo(imm()?),
+        "xhi" => Insn::Xhi(imm()?),
+        _ => return Err("unknown mnemonic"),
+    })
+}
+
+/// The `Jump` immediate without `JUMP_IF_ZERO`.
+fn jump_target(arg: &str) -> Result<u32, &'static str> {
+    let (neg, off) = if let Some(off) = arg.strip_prefix('+') {
+        (false, off)
+    } else if let Some(off) = arg.strip_prefix('-') {
+        (true, off)
+    } else {
+        let target = number(arg).ok_or("jump target isn't a number")?;
+        if target & JUMP_TARGET_MASK != target {
+            return Err("jump target doesn't fit in bits 26..0");
+        }
+        if target & 0x3 != 0 {
+            return Err("jump target isn't word-aligned");
+        }
+        return Ok(target);
+    };
+    let off = number(off).ok_or("jump offset isn't a number")?;
+    if off & 0x3 != 0 {
+        return Err("jump offset isn't a multiple of 4");
+    }
+    // The offset is signed and 27 bits wide.
+    let limit = JUMP_RELATIVE / 2;
+    if neg && off <= limit {
+        Ok(JUMP_RELATIVE | (off.wrapping_neg() & JUMP_TARGET_MASK))
+    } else if !neg && off < limit {
+        Ok(JUMP_RELATIVE | off)
+    } else {
+        Err("jump offset doesn't fit in bits 26..0")
+    }
+}
+
+fn number(s: &str) -> Option<u32> {
+    let (digits, radix) = if let Some(h) = s.strip_prefix("0x") {
+        (h, 16)
+    } else if let Some(h) = s.strip_prefix('#') {
+        (h, 16)
+    } else {
+        (s, 10)
+    };
+    if digits.starts_with('_') || digits.ends_with('_') {
+        return None;
+    }
+    let digits: String = digits.chars().filter(|&c| c != '_').collect();
+    if digits.starts_with('+') {
+        return None;
+    }
+    u32::from_str_radix(&digits, radix).ok()
+}
+
+/// One line per word: its byte address, then the instruction. A word that
+/// doesn't encode a valid instruction still shows its raw immediate,
+/// followed by a comment saying what's wrong with it.
+pub fn disassemble(code: &[u32]) -> String {
+    let mut s = String::new();
+    for (i, &word) in code.iter().enumerate() {
+        let insn = Insn::from_u32(word);
+        s += &format!("#{}  {}", friendly_hex_u32(4*i as u32), insn);
+        if let Err(e) = insn.validate() {
+            s += &format!("  ; {}", e);
+        }
+        s.push('\n');
+    }
+    s
+}
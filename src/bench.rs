@@ -0,0 +1,82 @@
+//! Canonical workloads for benchmarking and profiling `step`.
+//!
+//! Each workload is scaled up by its parameter: `tight_loop` counts down in
+//! a loop of cheap instructions, `deep_recursion` pushes and pops a deep
+//! stack of call frames, and `alloc_heavy` defines many items in one frame.
+//! Only `tight_loop` branches; the others are straight-line code.
+
+use crate::{Fault, Inherent, Insn, Machine, StopReason};
+use crate::{JUMP_IF_ZERO, JUMP_RELATIVE, JUMP_TARGET_MASK};
+
+pub struct Workload {
+    pub name: &'static str,
+    pub code: Vec<u32>,
+    steps: u64,
+}
+
+impl Workload {
+    pub fn machine(&self) -> Machine {
+        Machine::new(&self.code)
+    }
+
+    /// Run a fresh machine on the workload for at most `max_steps`.
+    pub fn run_with_limit(&self, max_steps: u64)
+        -> Result<StopReason, Fault>
+    {
+        self.machine().run_while(|_| true, max_steps)
+    }
+
+    /// How many steps a full run takes.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// A workload that runs each instruction once.
+    fn straight(name: &'static str, code: Vec<u32>) -> Self {
+        let steps = code.len() as u64;
+        Workload { name, code, steps }
+    }
+}
+
+fn encode(insns: impl IntoIterator<Item = Insn>) -> Vec<u32> {
+    insns.into_iter().map(|i| i.as_u32()).collect()
+}
+
+/// Count down from `n` (at least 1) in a loop of five instructions:
+/// subtract one, store the counter, and jump back unless it's hit zero.
+pub fn tight_loop(n: u32) -> Workload {
+    let n = n.max(1);
+    let [lo, hi] = Insn::load_u32(n);
+    let code = encode(vec![
+        lo, hi, Insn::Def(1),
+        // The loop, at byte 12.
+        Insn::Xlo(1),
+        Insn::Inh(Inherent::Sub),
+        Insn::Def(1),
+        Insn::Jump(JUMP_IF_ZERO | JUMP_RELATIVE | 8),
+        Insn::Jump(JUMP_RELATIVE | (-16i32 as u32 & JUMP_TARGET_MASK)),
+        Insn::Def(0),
+    ]);
+    // Every round takes five steps, except that the last one leaves
+    // through the `jumpz` and runs the final `def` instead of jumping back.
+    let steps = 3 + 5*n as u64;
+    Workload { name: "tight_loop", code, steps }
+}
+
+/// Push `depth` empty call frames, then pop them all.
+pub fn deep_recursion(depth: u32) -> Workload {
+    let insns = Some(Insn::Xlo(0)).into_iter()
+        .chain((0..depth).map(|_| Insn::Push(0)))
+        .chain((0..depth).map(|_| Insn::Inh(Inherent::Pop)))
+        .chain(Some(Insn::Def(0)));
+    Workload::straight("deep_recursion", encode(insns))
+}
+
+/// Define `n` distinct I32 items in the root frame, growing it one item at
+/// a time. Each `Def` also searches the frame, so this is quadratic.
+pub fn alloc_heavy(n: u32) -> Workload {
+    let insns = Some(Insn::Xlo(0)).into_iter()
+        .chain((1..=n).map(Insn::Def))
+        .chain(Some(Insn::Def(0)));
+    Workload::straight("alloc_heavy", encode(insns))
+}
@@ -0,0 +1,85 @@
+//! How numbers look in dumps.
+
+use std::io::IsTerminal;
+
+use crate::ItemId;
+
+/// How `Machine::write_stack_with` prints addresses, header fields, and
+/// ids.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FormatStyle {
+    /// `#0000_001C`, the default.
+    #[default]
+    Grouped,
+    /// `0x1c`
+    Hex,
+    /// `28`
+    Decimal,
+}
+
+impl FormatStyle {
+    pub fn u32(self, x: u32) -> String {
+        match self {
+            FormatStyle::Grouped => format!("#{}", friendly_hex_u32(x)),
+            FormatStyle::Hex => format!("{:#x}", x),
+            FormatStyle::Decimal => format!("{}", x),
+        }
+    }
+
+    pub fn id(self, id: ItemId) -> String {
+        self.u32(id.get())
+    }
+}
+
+/// `XXXX_XXXX`, in uppercase hex.
+pub fn friendly_hex_u32(x: u32) -> String {
+    format!("{:04X}_{:04X}", x >> 16, x & 0xFFFF)
+}
+
+/// Whether dumps use ANSI colors.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ColorMode {
+    #[default]
+    Never,
+    Always,
+    /// Color only if stdout is a terminal.
+    Auto,
+}
+
+impl ColorMode {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorMode::Never => false,
+            ColorMode::Always => true,
+            ColorMode::Auto => std::io::stdout().is_terminal(),
+        }
+    }
+}
+
+/// Wraps pieces of a dump in ANSI escapes, or leaves them alone.
+#[derive(Clone, Copy)]
+pub(crate) struct Paint(pub(crate) bool);
+
+impl Paint {
+    fn wrap(self, code: &str, s: String) -> String {
+        if self.0 {
+            format!("\x1B[{}m{}\x1B[0m", code, s)
+        } else {
+            s
+        }
+    }
+
+    /// Addresses: frame headers and pointer fields.
+    pub(crate) fn addr(self, s: String) -> String {
+        self.wrap("36", s)
+    }
+
+    pub(crate) fn ty(self, s: String) -> String {
+        self.wrap("33", s)
+    }
+
+    /// The header line of the frame `fp` points at.
+    pub(crate) fn current(self, s: String) -> String {
+        self.wrap("1;32", s)
+    }
+}
@@ -0,0 +1,324 @@
+//! Instructions: what each opcode means, how it's encoded in a code word,
+//! and decoding a stream of them.
+
+use core::convert::TryInto;
+use core::fmt;
+
+use crate::format::friendly_hex_u32;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Inherent {
+    Call,
+    Pop,
+    Alloc,
+    /// Set X to the current frame. The pointer dangles once that frame is
+    /// popped, and nothing checks for that, so don't keep it longer.
+    Frame,
+    /// Set X to `Code` pointing at the next instruction, so a later `Call`
+    /// on it continues after this one rather than running it again.
+    Pc,
+    /// The arithmetic inherents take their left operand from the last item
+    /// in the current frame, which they remove, and their right operand
+    /// from X. Both have to be `I32`. The result goes in X. `Add`, `Sub`,
+    /// and `Mul` wrap around on overflow.
+    Add,
+    Sub,
+    Mul,
+    /// -1, 0, or 1 as the left operand is less than, equal to, or greater
+    /// than the right one, both taken as signed.
+    Cmp,
+    Unknown(UnknownInherent),
+}
+
+/// The number of an inherent that isn't one of the known ones, so
+/// `Inherent::Unknown` always encodes as itself.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnknownInherent(u32);
+
+impl UnknownInherent {
+    pub const fn new(n: u32) -> Result<UnknownInherent, &'static str> {
+        if n <= Inherent::Cmp.as_u32() {
+            Err("inherent number is a known inherent")
+        } else if n & 0xE000_0000 != 0 {
+            Err("inherent number doesn't fit in bits 28..0")
+        } else {
+            Ok(UnknownInherent(n))
+        }
+    }
+
+    pub const fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Inherent {
+    pub(crate) fn from_u32(n: u32) -> Inherent {
+        use Inherent::*;
+        match n {
+            0 => Call,
+            1 => Pop,
+            2 => Alloc,
+            3 => Frame,
+            4 => Pc,
+            5 => Add,
+            6 => Sub,
+            7 => Mul,
+            8 => Cmp,
+            n => Unknown(UnknownInherent(n)),
+        }
+    }
+
+    pub(crate) const fn as_u32(&self) -> u32 {
+        use Inherent::*;
+        match *self {
+            Call => 0,
+            Pop => 1,
+            Alloc => 2,
+            Frame => 3,
+            Pc => 4,
+            Add => 5,
+            Sub => 6,
+            Mul => 7,
+            Cmp => 8,
+            Unknown(n) => n.0,
+        }
+    }
+}
+
+/// `call`, `pop`, and so on, or the bare number if it's `Unknown`.
+impl fmt::Display for Inherent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Inherent::*;
+        match *self {
+            Call => f.write_str("call"),
+            Pop => f.write_str("pop"),
+            Alloc => f.write_str("alloc"),
+            Frame => f.write_str("frame"),
+            Pc => f.write_str("pc"),
+            Add => f.write_str("add"),
+            Sub => f.write_str("sub"),
+            Mul => f.write_str("mul"),
+            Cmp => f.write_str("cmp"),
+            Unknown(n) => write!(f, "{}", n.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Insn {
+    Def(u32),
+    Set(u32),
+    Push(u32),
+    Inh(Inherent),
+    /// Bits 26..0 are a byte address, or with `JUMP_RELATIVE` a signed
+    /// byte offset from this instruction. With `JUMP_IF_ZERO` the jump is
+    /// only taken if X is `I32(0)`.
+    Jump(u32),
+    Val(u32),
+    Xlo(u32),
+    Xhi(u32),
+}
+
+impl Insn {
+    pub fn from_u32(i: u32) -> Self {
+        use Insn::*;
+        let op = i >> 29;
+        let n = i & 0x1FFF_FFFF;
+        match op {
+            0 => Def(n),
+            1 => Set(n),
+            2 => Push(n),
+            3 => Inh(Inherent::from_u32(n)),
+            4 => Jump(n),
+            5 => Val(n),
+            6 => Xlo(n),
+            7 => Xhi(n),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Encode the instruction, panicking if it's invalid. In a `const` an
+    /// invalid instruction is a compile error.
+    pub const fn as_u32(&self) -> u32 {
+        if let Err(e) = self.validate() {
+            panic!("{}", e);
+        }
+        self.encode()
+    }
+
+    pub fn try_as_u32(&self) -> Result<u32, &'static str> {
+        self.validate()?;
+        Ok(self.encode())
+    }
+
+    // Opcode 0 is shifted like the others to keep the table regular.
+    #[allow(clippy::identity_op)]
+    const fn encode(&self) -> u32 {
+        use Insn::*;
+        match *self {
+            Def(n) => (0<<29) | n,
+            Set(n) => (1<<29) | n,
+            Push(n) => (2<<29) | n,
+            Inh(i) => (3<<29) | i.as_u32(),
+            Jump(n) => (4<<29) | n,
+            Val(n) => (5<<29) | n,
+            Xlo(n) => (6<<29) | n,
+            Xhi(n) => (7<<29) | n,
+        }
+    }
+
+    /// Set X to `I32(value)`: `Xlo` with the low 29 bits, then `Xhi` with
+    /// the top 3.
+    pub const fn load_u32(value: u32) -> [Insn; 2] {
+        [Insn::Xlo(value & 0x1FFF_FFFF), Insn::Xhi(value >> 29)]
+    }
+
+    /// Where a `Jump` at `pc` goes if it's taken. Relative targets wrap
+    /// around the address space rather than failing, so check the result.
+    pub const fn jump_target(&self, pc: u32) -> Option<u32> {
+        match *self {
+            Insn::Jump(n) => {
+                let off = n & JUMP_TARGET_MASK;
+                if n & JUMP_RELATIVE == 0 {
+                    Some(off)
+                } else {
+                    // Sign-extend from bit 26.
+                    Some(pc.wrapping_add((((off << 5) as i32) >> 5) as u32))
+                }
+            },
+            _ => None,
+        }
+    }
+
+    pub const fn validate(&self) -> Result<(), &'static str> {
+        use Insn::*;
+        let imm = match *self {
+            Def(n) => n,
+            Set(n) => n,
+            Push(n) => n,
+            Inh(i) => i.as_u32(),
+            Jump(n) => n,
+            Val(n) => n,
+            Xlo(n) => n,
+            Xhi(n) => {
+                if n & 0x7 != n {
+                    return Err("xhi immediate doesn't fit in bits 2..0");
+                }
+                n
+            },
+        };
+        if imm & 0xE000_0000 == 0 {
+            Ok(())
+        } else {
+            Err("immediate doesn't fit in bits 28..0")
+        }
+    }
+}
+
+/// The mnemonic and its immediate: ids in decimal, `xlo` in `0x` hex, and
+/// absolute jump targets as `#` addresses. A conditional jump is `jumpz`,
+/// and a relative one shows its signed offset, like `jump -8`. Nothing is
+/// validated, so an oversized `xhi` immediate prints as it is.
+impl fmt::Display for Insn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Insn::*;
+        match *self {
+            Def(n) => write!(f, "def {}", n),
+            Set(n) => write!(f, "set {}", n),
+            Push(n) => write!(f, "push {}", n),
+            Inh(i) => write!(f, "inh {}", i),
+            Jump(n) => {
+                let op = if n & JUMP_IF_ZERO == 0 { "jump" } else { "jumpz" };
+                f.write_str(op)?;
+                let off = n & JUMP_TARGET_MASK;
+                if n & JUMP_RELATIVE == 0 {
+                    write!(f, " #{}", friendly_hex_u32(off))
+                } else {
+                    write!(f, " {:+}", ((off << 5) as i32) >> 5)
+                }
+            },
+            Val(n) => write!(f, "val {}", n),
+            Xlo(n) => write!(f, "xlo 0x{}", friendly_hex_u32(n)),
+            Xhi(n) => write!(f, "xhi {}", n),
+        }
+    }
+}
+
+/// `Jump` flag: only jump if X is `I32(0)`.
+pub const JUMP_IF_ZERO: u32 = 1 << 28;
+/// `Jump` flag: the target is relative to the jump.
+pub const JUMP_RELATIVE: u32 = 1 << 27;
+pub(crate) const JUMP_TARGET_MASK: u32 = JUMP_RELATIVE - 1;
+
+/// A `Jump` immediate moved `delta` bytes further along. Relative jumps
+/// don't move, and the flags are kept; `None` if the target no longer fits
+/// in bits 26..0.
+pub(crate) fn relocate_jump(n: u32, delta: u32) -> Option<u32> {
+    if n & JUMP_RELATIVE != 0 {
+        return Some(n);
+    }
+    let target = (n & JUMP_TARGET_MASK).checked_add(delta)
+        .filter(|&t| t & JUMP_TARGET_MASK == t)?;
+    Some((n & !JUMP_TARGET_MASK) | target)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The code ends `len` bytes into a word, at byte `offset`.
+    PartialWord { offset: usize, len: usize },
+}
+
+/// Decodes a stream of code words in order. Every instruction is one word
+/// for now.
+pub struct InsnDecoder<'a> {
+    src: DecoderSource<'a>,
+    pos: usize,
+}
+
+enum DecoderSource<'a> {
+    Words(&'a [u32]),
+    /// Little-endian, as in `Mem` and object files.
+    Bytes(&'a [u8]),
+}
+
+impl<'a> InsnDecoder<'a> {
+    pub fn new(code: &'a [u32]) -> Self {
+        Self { src: DecoderSource::Words(code), pos: 0 }
+    }
+
+    /// Decode raw little-endian bytes. If the length isn't a multiple of
+    /// four, the last item is a `DecodeError::PartialWord`.
+    pub fn from_bytes(code: &'a [u8]) -> Self {
+        Self { src: DecoderSource::Bytes(code), pos: 0 }
+    }
+}
+
+impl<'a> Iterator for InsnDecoder<'a> {
+    type Item = Result<Insn, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.src {
+            DecoderSource::Words(w) => {
+                let i = *w.get(self.pos)?;
+                self.pos += 1;
+                Some(Ok(Insn::from_u32(i)))
+            },
+            DecoderSource::Bytes(b) => {
+                let rest = b.get(self.pos..).filter(|r| !r.is_empty())?;
+                let offset = self.pos;
+                match rest.get(..4) {
+                    Some(w) => {
+                        self.pos += 4;
+                        let i = u32::from_le_bytes(w.try_into().unwrap());
+                        Some(Ok(Insn::from_u32(i)))
+                    },
+                    None => {
+                        self.pos = b.len();
+                        let len = rest.len();
+                        Some(Err(DecodeError::PartialWord { offset, len }))
+                    },
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! JSON interchange for instructions.
+//!
+//! Every instruction is an object `{ "op": <mnemonic>, "imm": <u32> }`. For
+//! `inh` the immediate is the inherent's number (0 = call, 1 = pop,
+//! 2 = alloc, 3 = frame, 4 = pc, 5 = add, 6 = sub, 7 = mul, 8 = cmp).
+
+use crate::{Inherent, Insn};
+
+/// Mnemonics in opcode order, so `OPS[op]` names opcode `op`.
+pub(crate) const OPS: [&str; 8] =
+    ["def", "set", "push", "inh", "jump", "val", "xlo", "xhi"];
+
+/// Largest immediate that fits in bits 28..0.
+const IMM_MAX: u32 = 0x1FFF_FFFF;
+
+/// Arrays and objects nested deeper than this are refused, so hostile input
+/// can't overflow the parser's stack. A program only needs two levels.
+const MAX_DEPTH: usize = 64;
+
+/// A JSON Schema (draft 2020-12) for a program: an array of instruction
+/// objects, with each immediate's range checked per opcode the same way as
+/// `Insn::validate`.
+pub fn insn_schema() -> String {
+    let mut variants = Vec::new();
+    for &op in OPS.iter() {
+        let max = if op == "xhi" { 0x7 } else { IMM_MAX };
+        variants.push(format!(
+            concat!(
+                "{{\"type\":\"object\",",
+                "\"properties\":{{",
+                "\"op\":{{\"const\":\"{}\"}},",
+                "\"imm\":{{\"type\":\"integer\",",
+                "\"minimum\":0,\"maximum\":{}}}}},",
+                "\"required\":[\"op\",\"imm\"],",
+                "\"additionalProperties\":false}}",
+            ),
+            op, max,
+        ));
+    }
+    format!(
+        concat!(
+            "{{\"$schema\":\"https://json-schema.org/draft/2020-12/schema\",",
+            "\"title\":\"lob program\",",
+            "\"type\":\"array\",",
+            "\"items\":{{\"$ref\":\"#/$defs/insn\"}},",
+            "\"$defs\":{{\"insn\":{{\"oneOf\":[{}]}}}}}}",
+        ),
+        variants.join(","),
+    )
+}
+
+/// Why `load_json_program` rejected its input.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum JsonError {
+    /// The text isn't JSON, or isn't an array; `pos` is a byte offset.
+    Syntax { pos: usize, msg: &'static str },
+    /// Element `index` of the array isn't a valid instruction.
+    Insn { index: usize, msg: &'static str },
+}
+
+/// Parse a JSON array of instruction objects (see the module docs) and
+/// check each one with `Insn::validate`.
+pub fn load_json_program(s: &str) -> Result<Vec<Insn>, JsonError> {
+    let mut p = Parser { s: s.as_bytes(), pos: 0, depth: 0 };
+    let v = p.value()?;
+    p.ws();
+    if p.pos != p.s.len() {
+        return Err(p.err("trailing characters"));
+    }
+    let elems = match v {
+        Json::Array(elems) => elems,
+        _ => return Err(JsonError::Syntax { pos: 0, msg: "expected an array" }),
+    };
+    elems.into_iter().enumerate()
+        .map(|(index, v)| {
+            to_insn(v).map_err(|msg| JsonError::Insn { index, msg })
+        })
+        .collect()
+}
+
+fn to_insn(v: Json) -> Result<Insn, &'static str> {
+    let fields = match v {
+        Json::Object(fields) => fields,
+        _ => return Err("expected an object"),
+    };
+    let mut op = None;
+    let mut imm = None;
+    for (k, v) in fields {
+        let slot = match k.as_str() {
+            "op" => &mut op,
+            "imm" => &mut imm,
+            _ => return Err("unknown field"),
+        };
+        if slot.replace(v).is_some() {
+            return Err("duplicate field");
+        }
+    }
+    let op = match op.ok_or("missing \"op\"")? {
+        Json::String(s) => OPS.iter().position(|&o| o == s)
+            .ok_or("unknown op")?,
+        _ => return Err("\"op\" must be a string"),
+    };
+    let imm = match imm.ok_or("missing \"imm\"")? {
+        Json::Number(n) => n.parse::<u32>()
+            .map_err(|_| "\"imm\" must be an integer in 0..2^32")?,
+        _ => return Err("\"imm\" must be a number"),
+    };
+    let insn = match op {
+        0 => Insn::Def(imm),
+        1 => Insn::Set(imm),
+        2 => Insn::Push(imm),
+        3 => Insn::Inh(Inherent::from_u32(imm)),
+        4 => Insn::Jump(imm),
+        5 => Insn::Val(imm),
+        6 => Insn::Xlo(imm),
+        7 => Insn::Xhi(imm),
+        _ => unreachable!(),
+    };
+    insn.validate()?;
+    Ok(insn)
+}
+
+enum Json {
+    Null,
+    /// No field takes a boolean, so which one doesn't matter.
+    Bool,
+    /// The literal text, so integers don't go through f64.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+    /// Arrays and objects open around `pos`.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn err(&self, msg: &'static str) -> JsonError {
+        JsonError::Syntax { pos: self.pos, msg }
+    }
+
+    fn ws(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') =
+            self.s.get(self.pos)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn keyword(&mut self, word: &str, v: Json) -> Result<Json, JsonError> {
+        if self.s[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(v)
+        } else {
+            Err(self.err("unexpected character"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.ws();
+        match self.peek() {
+            None => Err(self.err("unexpected end of input")),
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool),
+            Some(b'f') => self.keyword("false", Json::Bool),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(c @ b'[') | Some(c @ b'{') => {
+                if self.depth == MAX_DEPTH {
+                    return Err(self.err("nested too deeply"));
+                }
+                self.pos += 1;
+                self.depth += 1;
+                let v = if c == b'[' { self.array() } else { self.object() };
+                self.depth -= 1;
+                v
+            },
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.err("unexpected character")),
+        }
+    }
+
+    /// The rest of an array, after the `[`.
+    fn array(&mut self) -> Result<Json, JsonError> {
+        let mut elems = Vec::new();
+        self.ws();
+        if self.eat(b']') {
+            return Ok(Json::Array(elems));
+        }
+        loop {
+            elems.push(self.value()?);
+            self.ws();
+            if self.eat(b']') {
+                return Ok(Json::Array(elems));
+            }
+            if !self.eat(b',') {
+                return Err(self.err("expected ',' or ']'"));
+            }
+        }
+    }
+
+    /// The rest of an object, after the `{`.
+    fn object(&mut self) -> Result<Json, JsonError> {
+        let mut fields = Vec::new();
+        self.ws();
+        if self.eat(b'}') {
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.ws();
+            if self.peek() != Some(b'"') {
+                return Err(self.err("expected a string key"));
+            }
+            let k = self.string()?;
+            self.ws();
+            if !self.eat(b':') {
+                return Err(self.err("expected ':'"));
+            }
+            fields.push((k, self.value()?));
+            self.ws();
+            if self.eat(b'}') {
+                return Ok(Json::Object(fields));
+            }
+            if !self.eat(b',') {
+                return Err(self.err("expected ',' or '}'"));
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        self.eat(b'-');
+        let digits = |p: &mut Self| {
+            let from = p.pos;
+            while let Some(b'0'..=b'9') = p.peek() {
+                p.pos += 1;
+            }
+            p.pos > from
+        };
+        let int = self.pos;
+        if !digits(self) {
+            return Err(self.err("expected a digit"));
+        }
+        if self.s[int] == b'0' && self.pos > int + 1 {
+            return Err(JsonError::Syntax { pos: int, msg: "leading zero" });
+        }
+        if self.eat(b'.') && !digits(self) {
+            return Err(self.err("expected a digit"));
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            if !digits(self) {
+                return Err(self.err("expected a digit"));
+            }
+        }
+        // Only ASCII was consumed, so this can't split a character.
+        let text = core::str::from_utf8(&self.s[start..self.pos]).unwrap();
+        Ok(Json::Number(text.to_string()))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.s.get(self.pos..self.pos + 4)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| core::str::from_utf8(h).ok())
+            .and_then(|h| u32::from_str_radix(h, 16).ok())
+            .ok_or_else(|| self.err("bad \\u escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1; // opening quote
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while let Some(c) = self.peek() {
+                if c == b'"' || c == b'\\' || c < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            // The input was a &str and we stopped on an ASCII byte, so the
+            // run is whole characters.
+            out += core::str::from_utf8(&self.s[start..self.pos]).unwrap();
+            match self.peek() {
+                None => return Err(self.err("unterminated string")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                },
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let mut c = self.hex4()?;
+                            if (0xD800..0xDC00).contains(&c)
+                                && self.s[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let lo = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&lo) {
+                                    return Err(self.err("bad surrogate pair"));
+                                }
+                                c = 0x1_0000
+                                    + ((c - 0xD800) << 10) + (lo - 0xDC00);
+                            }
+                            out.push(core::char::from_u32(c)
+                                .ok_or_else(|| self.err("bad \\u escape"))?);
+                            continue;
+                        },
+                        _ => return Err(self.err("bad escape")),
+                    };
+                    self.pos += 1;
+                    out.push(c);
+                },
+                Some(_) => return Err(self.err("control character in string")),
+            }
+        }
+    }
+}
@@ -0,0 +1,2923 @@
+use core::convert::TryInto;
+use core::fmt;
+use std::io::{self, Read, Write};
+
+mod asm;
+pub mod bench;
+pub mod format;
+mod insn;
+#[cfg(feature = "json")]
+mod json;
+pub mod link;
+mod machine;
+mod mem;
+mod pool;
+#[cfg(feature = "server")]
+pub mod server;
+mod session;
+#[cfg(feature = "trace-events")]
+mod trace;
+mod verify;
+
+#[cfg(feature = "json")]
+pub use json::{insn_schema, load_json_program, JsonError};
+pub use asm::{
+    assemble, assemble_module, assemble_object, assemble_with_names,
+    disassemble, AssembleError, ItemNames,
+};
+pub use format::{ColorMode, FormatStyle};
+pub use insn::{
+    DecodeError, Inherent, Insn, InsnDecoder, UnknownInherent, JUMP_IF_ZERO,
+    JUMP_RELATIVE,
+};
+pub use machine::{
+    CodeWritePolicy, Event, Frames, Machine, MachineBuilder, ScopeMode,
+    StepEffect, StepInfo, StopReason, BUILTIN_CLOCK, BUILTIN_RANDOM,
+};
+pub use mem::{
+    item_header_from_u32, item_header_to_u32, try_item_header_from_u32,
+    FrameKind, HeaderLayout, ItemId, ItemPtr, Items, Mem, ObjPtr, Type,
+    WriteLog, XData, HEADER_LAYOUT_V1,
+};
+pub use pool::MachinePool;
+pub use session::{Session, Value};
+#[cfg(feature = "trace-events")]
+pub use trace::TraceEvent;
+pub use verify::{verify, VerifyError};
+
+use format::friendly_hex_u32;
+use insn::{relocate_jump, JUMP_TARGET_MASK};
+
+#[derive(Clone, Copy, Debug)]
+pub enum InsnException {
+    WrongType,
+    UnalignedCap,
+    NotTopFrame,
+    ItemNotFound,
+    ItemExistsInFrame(ItemPtr),
+    FrameFull,
+    StackUnderflow,
+    OutOfBounds(u32),
+    CorruptFrame(ObjPtr, &'static str),
+    UnknownBuiltin(u32),
+    CapabilityDenied(u32),
+    CorruptState(&'static str),
+    Unimplemented(Insn),
+    SizeOverflow,
+    /// A store to this address, which is below `Machine::code_end`.
+    WriteToCode(u32),
+    /// The same, under `CodeWritePolicy::Trap`.
+    SelfModifyingCode(u32),
+    /// A frame's `ret` isn't the address of an instruction.
+    BadReturnTarget(u32),
+    /// `pc` is past the end of the code.
+    PcOutOfCode(u32),
+    /// An instruction whose immediate `Insn::validate` rejects, such as an
+    /// `Xhi` with bits set above bit 2.
+    BadImmediate(Insn),
+    /// A taken `Jump` to this address, which isn't an instruction.
+    BadJumpTarget(u32),
+    /// `Push` of an existing object, but X points at something else: a
+    /// frame, something outside the stack, or an object that's already on
+    /// the `prev` chain.
+    NotAnObject(u32),
+    /// The `Machine::on_step` hook stopped the machine before this
+    /// instruction ran.
+    Interrupted,
+    /// Growing memory would take it past the machine's limit.
+    OutOfMemory,
+}
+
+impl fmt::Display for InsnException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InsnException::*;
+        let hex = friendly_hex_u32;
+        match *self {
+            WrongType => f.write_str("X has the wrong type"),
+            UnalignedCap => f.write_str("capacity isn't a multiple of 4"),
+            NotTopFrame => f.write_str("frame isn't on top of the stack"),
+            ItemNotFound => f.write_str("item not found"),
+            ItemExistsInFrame(p) => {
+                write!(f, "item already exists in frame at #{}", hex(p.0))
+            },
+            FrameFull => f.write_str("frame is full"),
+            StackUnderflow => f.write_str("stack underflow"),
+            OutOfBounds(a) => write!(f, "#{} is out of bounds", hex(a)),
+            CorruptFrame(p, why) => {
+                write!(f, "frame at #{} is corrupt: {}", hex(p.addr()), why)
+            },
+            UnknownBuiltin(n) => write!(f, "no builtin {}", n),
+            CapabilityDenied(n) => {
+                write!(f, "builtin {} isn't allowed on this machine", n)
+            },
+            CorruptState(why) => write!(f, "machine state is corrupt: {}", why),
+            Unimplemented(i) => write!(f, "`{}` isn't implemented", i),
+            SizeOverflow => f.write_str("size overflows"),
+            WriteToCode(a) => write!(f, "store to code at #{}", hex(a)),
+            SelfModifyingCode(a) => {
+                write!(f, "self-modifying store to code at #{}", hex(a))
+            },
+            BadReturnTarget(a) => {
+                write!(f, "return target #{} isn't an instruction", hex(a))
+            },
+            PcOutOfCode(a) => {
+                write!(f, "pc #{} is past the end of code", hex(a))
+            },
+            BadImmediate(i) => write!(f, "`{}` has a bad immediate", i),
+            BadJumpTarget(a) => {
+                write!(f, "jump target #{} isn't an instruction", hex(a))
+            },
+            NotAnObject(a) => {
+                write!(f, "#{} isn't an object that can be entered", hex(a))
+            },
+            Interrupted => f.write_str("interrupted by the step hook"),
+            OutOfMemory => f.write_str("out of memory"),
+        }
+    }
+}
+
+/// An exception together with the address of the instruction that raised it.
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    pub pc: u32,
+    pub kind: InsnException,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} @ #{}", self.kind, friendly_hex_u32(self.pc))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum LoadError {
+    BadMagic,
+    Truncated,
+    UnalignedCode,
+    BadEntry(u32),
+    UnsupportedVersion(u8),
+    ChecksumMismatch,
+    /// The program doesn't fit in the 32-bit address space.
+    TooLarge(u64),
+    Io(io::ErrorKind),
+    /// A relocation outside the code, or one whose result doesn't fit.
+    BadRelocation(u32),
+    UnalignedBase(u32),
+    /// A symbol outside the code, a bad symbol name, or a source map that
+    /// doesn't have one line per code word.
+    BadDebugInfo,
+}
+
+/// What `Machine::check_invariants` found wrong: the rule that doesn't
+/// hold, and the frame it doesn't hold for, if it's about one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IntegrityError {
+    pub frame: Option<ObjPtr>,
+    pub rule: &'static str,
+}
+
+impl IntegrityError {
+    fn at(frame: ObjPtr, e: InsnException) -> Self {
+        let rule = match e {
+            InsnException::CorruptFrame(_, why) => why,
+            InsnException::SizeOverflow => "offset overflows",
+            _ => "header or item is out of bounds",
+        };
+        IntegrityError { frame: Some(frame), rule }
+    }
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.frame {
+            Some(fp) => {
+                write!(f, "#{}: {}", friendly_hex_u32(fp.addr()), self.rule)
+            },
+            None => f.write_str(self.rule),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use LoadError::*;
+        let hex = friendly_hex_u32;
+        match *self {
+            BadMagic => f.write_str("not an object file"),
+            Truncated => f.write_str("object file is truncated"),
+            UnalignedCode => f.write_str("code isn't a whole number of words"),
+            BadEntry(a) => {
+                write!(f, "entry point #{} isn't in the code", hex(a))
+            },
+            UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            ChecksumMismatch => f.write_str("checksum doesn't match"),
+            TooLarge(n) => write!(f, "{} bytes of code is too much", n),
+            Io(kind) => write!(f, "read failed: {:?}", kind),
+            BadRelocation(a) => write!(f, "bad relocation at #{}", hex(a)),
+            UnalignedBase(a) => {
+                write!(f, "load address #{} isn't word-aligned", hex(a))
+            },
+            BadDebugInfo => f.write_str(
+                "symbol table or source map doesn't match the code"),
+        }
+    }
+}
+
+/// Why `Machine::run` stopped without halting.
+#[derive(Clone, Copy, Debug)]
+pub enum RunError {
+    Fault(Fault),
+    /// The budget ran out with the program still running.
+    StepLimitExceeded,
+}
+
+/// Everything `Machine::validate_and_run` can fail with.
+#[derive(Clone, Copy, Debug)]
+pub enum LobError {
+    Load(LoadError),
+    Verify(VerifyError),
+    Fault(Fault),
+    /// The program was still running after this many steps.
+    StepLimit(u64),
+}
+
+/// CRC-32 (IEEE, reflected), bit at a time. Images are small enough that a
+/// lookup table isn't worth it.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+const OBJ_FILE_MAGIC: [u8; 3] = *b"LOB";
+
+/// Set in the version byte when a relocation table follows the checksum.
+const OBJ_FILE_HAS_RELOCS: u8 = 0x80;
+
+/// Set in the version byte when a symbol table and source map follow the
+/// relocations.
+const OBJ_FILE_HAS_DEBUG: u8 = 0x40;
+
+/// On-disk layout (all words little-endian):
+///
+/// ```text
+/// magic "LOB", then one byte of header layout version
+/// entry (byte address of the first instruction to execute)
+/// checksum (CRC-32 of everything after it)
+/// if the version byte has bit 7 set:
+///     relocation count
+///     relocations (word indices into the code)...
+/// if the version byte has bit 6 set:
+///     symbol count
+///     for each symbol:
+///         address
+///         name length in bytes
+///         name (UTF-8, zero-padded to a whole word)
+///     source map length (0, or one per code word)
+///     source lines...
+/// code words...
+/// ```
+///
+/// Files without relocations leave bit 7 clear, and files without symbols
+/// or a source map leave bit 6 clear, so they have the same bytes as before
+/// those sections existed.
+#[derive(Clone, Default)]
+pub struct ObjectFile {
+    pub entry: u32,
+    pub code: Vec<u32>,
+    /// Indices of code words whose immediates are code addresses, assuming
+    /// the code starts at address 0. `relocate` adds the real base to them.
+    pub relocs: Vec<u32>,
+    /// Label names and their code addresses, relocated like `entry`.
+    pub symbols: Vec<(String, u32)>,
+    /// The source line each code word came from, or empty if unknown.
+    pub lines: Vec<u32>,
+}
+
+impl ObjectFile {
+    pub fn validate(&self) -> Result<(), LoadError> {
+        if self.entry & 0x3 != 0 || self.entry as usize >= 4*self.code.len() {
+            return Err(LoadError::BadEntry(self.entry));
+        }
+        for &r in &self.relocs {
+            if r as usize >= self.code.len() {
+                return Err(LoadError::BadRelocation(r));
+            }
+        }
+        // A label can sit just past the last instruction.
+        let end = 4*self.code.len() as u64;
+        if self.symbols.iter().any(|&(_, a)| a as u64 > end)
+            || !(self.lines.is_empty() || self.lines.len() == self.code.len())
+        {
+            return Err(LoadError::BadDebugInfo);
+        }
+        Ok(())
+    }
+
+    /// Move the code so it starts at `base`: adjust every relocated
+    /// immediate and the entry point, and drop the relocation table.
+    pub fn relocate(&mut self, base: u32) -> Result<(), LoadError> {
+        if base & 0x3 != 0 {
+            return Err(LoadError::UnalignedBase(base));
+        }
+        self.validate()?;
+        for &r in &self.relocs {
+            let word = self.code[r as usize];
+            let imm = word & 0x1FFF_FFFF;
+            let imm = match Insn::from_u32(word) {
+                Insn::Jump(n) => relocate_jump(n, base),
+                _ => imm.checked_add(base).filter(|&n| n & 0xE000_0000 == 0),
+            };
+            let imm = imm.ok_or(LoadError::BadRelocation(r))?;
+            self.code[r as usize] = (word & 0xE000_0000) | imm;
+        }
+        self.entry = self.entry.checked_add(base)
+            .ok_or(LoadError::BadEntry(self.entry))?;
+        for (_, a) in &mut self.symbols {
+            *a = a.checked_add(base).ok_or(LoadError::BadDebugInfo)?;
+        }
+        self.relocs.clear();
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(
+            16 + 4*self.relocs.len() + 4*self.code.len());
+        b.extend_from_slice(&OBJ_FILE_MAGIC);
+        let has_debug = !(self.symbols.is_empty() && self.lines.is_empty());
+        let mut version = HeaderLayout::CURRENT.version;
+        if !self.relocs.is_empty() {
+            version |= OBJ_FILE_HAS_RELOCS;
+        }
+        if has_debug {
+            version |= OBJ_FILE_HAS_DEBUG;
+        }
+        b.push(version);
+        b.extend_from_slice(&self.entry.to_le_bytes());
+        b.extend_from_slice(&[0; 4]);
+        if !self.relocs.is_empty() {
+            b.extend_from_slice(&(self.relocs.len() as u32).to_le_bytes());
+            for &r in &self.relocs {
+                b.extend_from_slice(&r.to_le_bytes());
+            }
+        }
+        if has_debug {
+            b.extend_from_slice(&(self.symbols.len() as u32).to_le_bytes());
+            for (name, addr) in &self.symbols {
+                b.extend_from_slice(&addr.to_le_bytes());
+                b.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                b.extend_from_slice(name.as_bytes());
+                b.resize((b.len() + 3) & !3, 0);
+            }
+            b.extend_from_slice(&(self.lines.len() as u32).to_le_bytes());
+            for &l in &self.lines {
+                b.extend_from_slice(&l.to_le_bytes());
+            }
+        }
+        for &i in &self.code {
+            b.extend_from_slice(&i.to_le_bytes());
+        }
+        let checksum = crc32(&b[12..]);
+        b[8..12].copy_from_slice(&checksum.to_le_bytes());
+        b
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Result<Self, LoadError> {
+        if b.len() < 12 {
+            return Err(LoadError::Truncated);
+        }
+        if b[0..3] != OBJ_FILE_MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let has_relocs = b[3] & OBJ_FILE_HAS_RELOCS != 0;
+        let has_debug = b[3] & OBJ_FILE_HAS_DEBUG != 0;
+        let version = b[3] & !(OBJ_FILE_HAS_RELOCS | OBJ_FILE_HAS_DEBUG);
+        // There's only one layout so far, but this is where an older image
+        // would pick its field offsets.
+        let _layout = HeaderLayout::for_version(version)
+            .ok_or(LoadError::UnsupportedVersion(version))?;
+        let entry = u32::from_le_bytes(b[4..8].try_into().unwrap());
+        let checksum = u32::from_le_bytes(b[8..12].try_into().unwrap());
+        let body = &b[12..];
+        if body.len() & 0x3 != 0 {
+            return Err(LoadError::UnalignedCode);
+        }
+        if crc32(body) != checksum {
+            return Err(LoadError::ChecksumMismatch);
+        }
+        let mut words = body.chunks(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()));
+        let mut relocs = Vec::new();
+        if has_relocs {
+            let count = words.next().ok_or(LoadError::Truncated)?;
+            if count as usize > words.len() {
+                return Err(LoadError::Truncated);
+            }
+            relocs.extend(words.by_ref().take(count as usize));
+        }
+        let mut symbols = Vec::new();
+        let mut lines = Vec::new();
+        if has_debug {
+            let count = words.next().ok_or(LoadError::Truncated)?;
+            for _ in 0..count {
+                let addr = words.next().ok_or(LoadError::Truncated)?;
+                let len = words.next().ok_or(LoadError::Truncated)? as usize;
+                let padded = len.div_ceil(4);
+                if padded > words.len() {
+                    return Err(LoadError::Truncated);
+                }
+                let name: Vec<u8> = words.by_ref().take(padded)
+                    .flat_map(u32::to_le_bytes).take(len).collect();
+                let name = String::from_utf8(name)
+                    .map_err(|_| LoadError::BadDebugInfo)?;
+                symbols.push((name, addr));
+            }
+            let count = words.next().ok_or(LoadError::Truncated)?;
+            if count as usize > words.len() {
+                return Err(LoadError::Truncated);
+            }
+            lines.extend(words.by_ref().take(count as usize));
+        }
+        let code = words.collect();
+        let obj = Self { entry, code, relocs, symbols, lines };
+        obj.validate()?;
+        Ok(obj)
+    }
+}
+
+/// Write `obj`, with its symbols and source map, in the object file format
+/// described on `ObjectFile`. This is lob's own format, not bincode or CBOR.
+pub fn save_program(w: &mut impl Write, obj: &ObjectFile)
+    -> Result<(), LoadError>
+{
+    w.write_all(&obj.to_bytes()).map_err(|e| LoadError::Io(e.kind()))
+}
+
+/// Read a whole object file from `r`.
+pub fn load_program(r: &mut impl Read) -> Result<ObjectFile, LoadError> {
+    let mut b = Vec::new();
+    r.read_to_end(&mut b).map_err(|e| LoadError::Io(e.kind()))?;
+    ObjectFile::from_bytes(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+    use core::ops::ControlFlow;
+
+    use super::*;
+    use mem::OBJ_HEADER_SIZE;
+
+    fn demo_prog() -> Vec<u32> {
+        encode(&[
+            Insn::Val(0),
+
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Push(1),
+            Insn::Xlo(0x1FFF_FFFF),
+            Insn::Xhi(0x7),
+            Insn::Def(1),
+            Insn::Def(2),
+            Insn::Def(3),
+            Insn::Def(4),
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Inh(Inherent::Pop),
+            Insn::Inh(Inherent::Pop),
+            Insn::Inh(Inherent::Pop),
+
+            Insn::Def(0),
+        ])
+    }
+
+    #[test]
+    fn const_encoding() {
+        const PROG: [u32; 3] = [
+            Insn::Xlo(9).as_u32(),
+            Insn::Def(0).as_u32(),
+            item_header_to_u32(Type::I32, ItemId(5)),
+        ];
+        assert_eq!(PROG[..2], encode(&[Insn::Xlo(9), Insn::Def(0)])[..]);
+        assert_eq!(PROG[2], 0x4000_0005);
+        assert_eq!(Insn::Xhi(7).try_as_u32(), Ok(0xE000_0007));
+        assert!(Insn::Xhi(8).try_as_u32().is_err());
+    }
+
+    #[test]
+    fn insn_round_trip() {
+        let insns = [
+            Insn::Def(1),
+            Insn::Set(0x1FFF_FFFF),
+            Insn::Push(0),
+            Insn::Inh(Inherent::Call),
+            Insn::Inh(Inherent::Pop),
+            Insn::Inh(Inherent::Alloc),
+            Insn::Jump(0x40),
+            Insn::Val(7),
+            Insn::Xlo(0x1234_5678),
+            Insn::Xhi(0x7),
+        ];
+        for &insn in insns.iter() {
+            assert_eq!(Insn::from_u32(insn.as_u32()), insn);
+        }
+        let inhs = [
+            Inherent::Call, Inherent::Pop, Inherent::Alloc, Inherent::Frame,
+            Inherent::Pc, Inherent::Add, Inherent::Sub, Inherent::Mul,
+            Inherent::Cmp,
+        ];
+        for &inh in inhs.iter() {
+            assert_eq!(Inherent::from_u32(inh.as_u32()), inh);
+        }
+    }
+
+    #[test]
+    fn opcode_selects_variant() {
+        for op in 0..=7u32 {
+            let insn = Insn::from_u32((op << 29) | 1);
+            let expected = match op {
+                0 => Insn::Def(1),
+                1 => Insn::Set(1),
+                2 => Insn::Push(1),
+                3 => Insn::Inh(Inherent::Pop),
+                4 => Insn::Jump(1),
+                5 => Insn::Val(1),
+                6 => Insn::Xlo(1),
+                7 => Insn::Xhi(1),
+                _ => unreachable!(),
+            };
+            assert_eq!(insn, expected);
+        }
+    }
+
+    #[test]
+    fn xhi_immediate_range() {
+        assert!(Insn::Xhi(0x7).validate().is_ok());
+        assert!(Insn::Xhi(0x8).validate().is_err());
+        assert!(Insn::Xlo(0x2000_0000).validate().is_err());
+        // A raw word can still carry a wide xhi immediate.
+        assert_eq!(Insn::from_u32(0xFFFF_FFFF), Insn::Xhi(0x1FFF_FFFF));
+        assert!(Insn::from_u32(0xFFFF_FFFF).validate().is_err());
+    }
+
+    fn encode(insns: &[Insn]) -> Vec<u32> {
+        insns.iter().map(|i| i.as_u32()).collect()
+    }
+
+    #[test]
+    fn push_frame() {
+        let mut m = Machine::new(&encode(&[Insn::Xlo(8), Insn::Push(0)]));
+        let root = m.fp;
+        assert_eq!(root.addr(), 8);
+        m.step().unwrap();
+        m.step().unwrap();
+
+        // The new frame sits right after the root's (empty) body.
+        let fp = m.fp;
+        assert_eq!(fp.addr(), 8 + OBJ_HEADER_SIZE);
+        assert_eq!(fp.cap(&m.mem), 8);
+        assert_eq!(fp.size(&m.mem), 0);
+        assert_eq!(fp.base(&m.mem), Some(root));
+        assert_eq!(fp.prev(&m.mem), Some(root));
+        assert_eq!(fp.ret(&m.mem), None);
+        assert_eq!(m.tos(), fp.body_offset(8));
+        assert_eq!(root.size(&m.mem), 0);
+    }
+
+    #[test]
+    fn push_named_object() {
+        let mut m = Machine::new(&encode(&[Insn::Xlo(8), Insn::Push(3)]));
+        let root = m.fp;
+        m.step().unwrap();
+        m.step().unwrap();
+
+        // The root grows to hold the item header, the object header, and
+        // the object's body.
+        let item = root.body_offset(0);
+        assert_eq!(root.size(&m.mem), 4 + OBJ_HEADER_SIZE + 8);
+        assert_eq!(root.cap(&m.mem), 4 + OBJ_HEADER_SIZE + 8);
+        let (ty, id) = item_header_from_u32(m.load_u32(item));
+        assert!(matches!(ty, Type::Object));
+        assert_eq!(id, ItemId(3));
+
+        let obj = m.fp;
+        assert_eq!(obj.addr(), item + 4);
+        assert_eq!(obj.cap(&m.mem), 8);
+        assert_eq!(obj.size(&m.mem), 0);
+        assert_eq!(obj.base(&m.mem), Some(root));
+        assert_eq!(obj.prev(&m.mem), Some(root));
+        assert_eq!(obj.ret(&m.mem), None);
+        assert_eq!(m.tos(), obj.body_offset(8));
+        assert_eq!(m.find_in_frame(root, ItemId(3)).unwrap().unwrap().0, item);
+    }
+
+    /// Push object 3 with `cap`, define `n` items in it, and pop back to
+    /// the root. Returns the machine and the object's final cap.
+    fn pop_named_object(cap: u32, n: u32) -> (Machine, u32) {
+        let mut insns = vec![Insn::Xlo(cap), Insn::Push(3), Insn::Xlo(0)];
+        insns.extend((1..=n).map(Insn::Def));
+        insns.push(Insn::Inh(Inherent::Pop));
+        let mut m = Machine::builder(&encode(&insns)).strict(true).build();
+        let root = m.fp;
+        for _ in 0..insns.len() - 1 {
+            m.step().unwrap();
+        }
+        let obj = m.fp;
+        let obj_cap = obj.cap(&m.mem);
+        assert_eq!(obj.size(&m.mem), 8*n);
+        m.step().unwrap();
+        assert_eq!(m.fp, root);
+        m.check_invariants().unwrap();
+        (m, obj_cap)
+    }
+
+    #[test]
+    fn pop_named_object_fixes_parent() {
+        for &(cap, n) in &[(8, 1), (16, 1), (16, 2), (0, 0), (0, 3), (8, 3)] {
+            let (m, obj_cap) = pop_named_object(cap, n);
+            assert_eq!(obj_cap, cap.max(8*n));
+            let size = 4 + OBJ_HEADER_SIZE + obj_cap;
+            assert_eq!(m.fp.size(&m.mem), size, "cap {}, {} items", cap, n);
+            assert_eq!(m.fp.cap(&m.mem), size, "cap {}, {} items", cap, n);
+            assert_eq!(m.tos(), m.fp.body_offset(size));
+            let item = m.find_in_frame(m.fp, ItemId(3)).unwrap().unwrap();
+            assert_eq!(item.0, m.fp.body_offset(0));
+        }
+    }
+
+    #[test]
+    fn pop_checks_ret() {
+        let code = encode(&[
+            Insn::Xlo(0), Insn::Push(0), Insn::Inh(Inherent::Pop),
+        ]);
+        let cases = [(4, true), (6, false), (12, false), (0x100, false)];
+        for &(ret, ok) in &cases {
+            let mut m = Machine::builder(&code).strict(true).build();
+            m.step().unwrap();
+            m.step().unwrap();
+            m.fp.set_ret(&mut m.mem, NonZeroU32::new(ret));
+            match m.step() {
+                Ok(None) if ok => assert_eq!(m.pc, ret),
+                Err(Fault { pc: 8, kind: InsnException::BadReturnTarget(r) })
+                    if !ok && r == ret => assert_eq!(m.pc, 8),
+                r => panic!("ret {}: got {:?}", ret, r),
+            }
+        }
+    }
+
+    #[test]
+    fn pop_root_exits() {
+        let mut m = Machine::new(&encode(&[
+            Insn::Xlo(0), Insn::Push(0), Insn::Xlo(9), Insn::Inh(Inherent::Pop),
+            Insn::Inh(Inherent::Pop),
+        ]));
+        match m.run_while(|_| true, 100) {
+            Ok(StopReason::Halted(XData::I32(9))) => (),
+            r => panic!("expected to halt with 9, got {:?}", r),
+        }
+        assert!(m.stack_empty());
+        assert_eq!(m.pc, 16);
+        match m.step() {
+            Err(Fault { pc: 16, kind: InsnException::StackUnderflow }) => (),
+            r => panic!("expected StackUnderflow, got {:?}", r),
+        }
+        m.reset();
+        assert!(!m.stack_empty());
+    }
+
+    #[test]
+    fn push_errors() {
+        let mut m = Machine::new(&encode(&[Insn::Xlo(6), Insn::Push(0)]));
+        m.step().unwrap();
+        assert!(matches!(
+            m.step(), Err(Fault { kind: InsnException::UnalignedCap, .. })));
+
+        let mut m = Machine::new(&encode(&[
+            Insn::Xlo(0), Insn::Push(0), Insn::Push(0),
+        ]));
+        m.step().unwrap();
+        m.step().unwrap();
+        // Go back to the root without popping, so it's no longer on top.
+        m.fp = m.gp;
+        assert!(matches!(
+            m.step(), Err(Fault { kind: InsnException::NotTopFrame, .. })));
+    }
+
+    #[test]
+    fn zero_cap_top_frame_grows() {
+        let mut m = Machine::new(&encode(&[
+            Insn::Xlo(0), Insn::Push(0), Insn::Xlo(5), Insn::Def(1),
+        ]));
+        for _ in 0..2 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.fp.cap(&m.mem), 0);
+        assert_eq!(m.tos(), m.fp.body_offset(0));
+        for _ in 0..2 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.fp.cap(&m.mem), 8);
+        assert_eq!(m.fp.size(&m.mem), 8);
+        assert_eq!(m.tos(), m.fp.body_offset(8));
+        m.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn zero_cap_buried_object_is_full() {
+        let mut m = Machine::new(&encode(&[
+            Insn::Xlo(0), Insn::Push(3), Insn::Push(0), Insn::Def(1),
+        ]));
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        // Step back into the object while the frame above it is still there.
+        let obj = m.fp.prev(&m.mem).unwrap();
+        m.fp = obj;
+        let tos = m.tos();
+        assert!(matches!(
+            m.step(), Err(Fault { kind: InsnException::FrameFull, .. })));
+        assert_eq!(obj.cap(&m.mem), 0);
+        assert_eq!(obj.size(&m.mem), 0);
+        assert_eq!(m.tos(), tos);
+    }
+
+    #[test]
+    fn push_size_overflow() {
+        for &id in [0, 1].iter() {
+            let mut m = Machine::new(&encode(&[
+                Insn::Xlo(0x1FFF_FFFC), Insn::Xhi(0x7), Insn::Push(id),
+            ]));
+            m.step().unwrap();
+            m.step().unwrap();
+            let (fp, tos) = (m.fp, m.tos());
+            match m.step() {
+                Err(Fault { kind: InsnException::SizeOverflow, .. }) => (),
+                r => panic!("expected SizeOverflow, got {:?}", r),
+            }
+            assert_eq!(m.fp, fp);
+            assert_eq!(m.tos(), tos);
+        }
+    }
+
+    /// Step to completion, checking invariants after every instruction.
+    fn run_checked(m: &mut Machine) -> Result<XData, Fault> {
+        m.check_invariants().unwrap();
+        loop {
+            let r = m.step();
+            m.check_invariants().unwrap();
+            if let Some(x) = r? {
+                return Ok(x);
+            }
+        }
+    }
+
+    #[test]
+    fn demo_keeps_invariants() {
+        let mut m = Machine::new(&demo_prog());
+        run_checked(&mut m).unwrap();
+    }
+
+    #[test]
+    fn separate_code() {
+        let code = demo_prog();
+        let mut m = Machine::builder(&code).separate_code(true).build();
+        assert_eq!(m.fp.addr(), 4);
+        assert_eq!(m.code_end(), 4);
+        assert_eq!(m.code_mem().len() as usize, 4*code.len());
+        let x = run_checked(&mut m).unwrap();
+
+        let mut unified = Machine::new(&code);
+        let y = run_checked(&mut unified).unwrap();
+        assert_eq!((m.pc, x.ty()), (unified.pc, y.ty()));
+        assert_eq!(m.max_frame_depth(), unified.max_frame_depth());
+        assert_eq!(m.mem.len() + 4*code.len() as u32 - 4, unified.mem.len());
+    }
+
+    #[test]
+    fn append_code() {
+        let mut m = Machine::builder(&encode(&[Insn::Val(0)]))
+            .separate_code(true)
+            .build();
+        m.step().unwrap();
+        let base = m.append_code(&encode(&[Insn::Xlo(7), Insn::Def(0)]));
+        assert_eq!(base, 4);
+        match m.step() {
+            Ok(None) => (),
+            r => panic!("expected to keep running, got {:?}", r),
+        }
+        match m.step() {
+            Ok(Some(XData::I32(7))) => (),
+            r => panic!("expected to halt with 7, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn base_cycle_is_reported() {
+        let prog = encode(&[Insn::Xlo(0), Insn::Push(0)]);
+        let mut m = Machine::new(&prog);
+        m.step().unwrap();
+        m.step().unwrap();
+        m.gp.set_base(&mut m.mem, Some(m.fp));
+        match m.find_lexical(ItemId(1)) {
+            Err(InsnException::CorruptFrame(..)) => (),
+            r => panic!("expected CorruptFrame, got {:?}", r),
+        }
+        let rule = "cycle in `base` chain";
+        assert_eq!(m.check_invariants(), Err(IntegrityError {
+            frame: Some(m.fp), rule,
+        }));
+    }
+
+    #[test]
+    fn object_file_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let obj = ObjectFile {
+            entry: 4, code: demo_prog(), ..Default::default()
+        };
+        let mut b = obj.to_bytes();
+        let loaded = ObjectFile::from_bytes(&b).unwrap();
+        assert_eq!(loaded.code, obj.code);
+        assert_eq!(loaded.entry, 4);
+
+        let m = Machine::load(&loaded).unwrap();
+        assert_eq!(
+            crc32(&m.mem.as_bytes()[..4*obj.code.len()]),
+            u32::from_le_bytes(b[8..12].try_into().unwrap()));
+
+        let mut saved = Vec::new();
+        save_program(&mut saved, &obj).unwrap();
+        assert_eq!(saved, b);
+        assert_eq!(load_program(&mut &saved[..]).unwrap().code, obj.code);
+
+        *b.last_mut().unwrap() ^= 1;
+        match ObjectFile::from_bytes(&b) {
+            Err(LoadError::ChecksumMismatch) => (),
+            r => panic!("expected ChecksumMismatch, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[cfg(feature = "trace-events")]
+    #[test]
+    fn trace_events() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let l = log.clone();
+        let code = encode(&[Insn::Xlo(5), Insn::Def(1), Insn::Def(0)]);
+        let mut m = Machine::new(&code);
+        m.on_trace(move |e| l.borrow_mut().push(e.to_string()));
+        m.run(10).unwrap();
+        assert_eq!(*log.borrow(), vec![
+            "lob step=0 pc=0x0 opcode=6 fp=0xc",
+            "lob step=1 pc=0x4 opcode=0 fp=0xc",
+            "lob step=1 event=item_defined id=0x1 type=I32",
+            "lob step=2 pc=0x8 opcode=0 fp=0xc",
+            "lob step=2 event=halted x=I32(5)",
+        ]);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn schema_covers_every_op() {
+        let schema = insn_schema();
+        let mut depth = 0i32;
+        for c in schema.chars() {
+            match c {
+                '{' | '[' => depth += 1,
+                '}' | ']' => depth -= 1,
+                _ => (),
+            }
+            assert!(depth >= 0);
+        }
+        assert_eq!(depth, 0);
+        for op in json::OPS.iter() {
+            assert!(schema.contains(&format!("{{\"const\":\"{}\"}}", op)));
+        }
+        assert!(schema.contains("\"maximum\":7}"));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_program() {
+        let insns = load_json_program(r#"[
+            { "op": "xlo", "imm": 5 },
+            { "op": "inh", "imm": 1 },
+            {"imm":7,"op":"xhi"}
+        ]"#).unwrap();
+        assert_eq!(insns, vec![
+            Insn::Xlo(5), Insn::Inh(Inherent::Pop), Insn::Xhi(7),
+        ]);
+        assert_eq!(load_json_program("[]").unwrap(), vec![]);
+
+        let insn_err = |s| match load_json_program(s) {
+            Err(JsonError::Insn { index, msg }) => (index, msg),
+            r => panic!("expected an Insn error, got {:?}", r),
+        };
+        assert_eq!(
+            insn_err(r#"[{"op":"val","imm":0},{"op":"xhi","imm":8}]"#),
+            (1, "xhi immediate doesn't fit in bits 2..0"));
+        assert_eq!(insn_err(r#"[{"op":"nop","imm":0}]"#).1, "unknown op");
+        assert_eq!(insn_err(r#"[{"op":"def","imm":-1}]"#).0, 0);
+        assert_eq!(insn_err(r#"[{"op":"def"}]"#).1, "missing \"imm\"");
+        assert_eq!(
+            insn_err(r#"[{"op":"def","imm":0,"x":1}]"#).1, "unknown field");
+        assert_eq!(insn_err(r#"[{"op":"def","imm":536870912}]"#).1,
+            "immediate doesn't fit in bits 28..0");
+
+        let syntax_err = |s| match load_json_program(s) {
+            Err(JsonError::Syntax { pos, msg }) => (pos, msg),
+            r => panic!("expected a syntax error, got {:?}", r),
+        };
+        assert_eq!(syntax_err(r#"[{"op":"xlo","imm":007}]"#),
+            (19, "leading zero"));
+        assert_eq!(syntax_err(r#"[{"op":"\u+06f"}]"#).1, "bad \\u escape");
+
+        match load_json_program(r#"[{"op":"def","imm":0}"#) {
+            Err(JsonError::Syntax { pos: 21, .. }) => (),
+            r => panic!("expected a syntax error, got {:?}", r),
+        }
+
+        // Deep nesting is refused before it can overflow the stack.
+        let deep = "[".repeat(1_000_000);
+        match load_json_program(&deep) {
+            Err(JsonError::Syntax { pos: 64, msg: "nested too deeply" }) => (),
+            r => panic!("expected a nesting error, got {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn server_round_trip() {
+        use std::net::{TcpListener, TcpStream};
+
+        // A header promising 16 MiB with two bytes behind it.
+        let short = [0, 0, 0, 1, 1, 2];
+        match server::read_message(&mut &short[..]) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            r => panic!("expected UnexpectedEof, got {:?}", r),
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let t = std::thread::spawn(move || {
+            server::handle(listener.accept().unwrap().0).unwrap();
+        });
+        let mut c = TcpStream::connect(addr).unwrap();
+        let mut call = |req: &[u8]| {
+            server::write_message(&mut c, req).unwrap();
+            server::read_message(&mut c).unwrap().unwrap()
+        };
+
+        assert_eq!(&call(&[1, 1])[..2], &[1, 1]); // nothing loaded yet
+        let obj = ObjectFile {
+            entry: 0, code: demo_prog(), ..Default::default()
+        };
+        let mut load = vec![1, 0];
+        load.extend_from_slice(&obj.to_bytes());
+        assert_eq!(call(&load), [1, 0]);
+        assert_eq!(call(&[1, 1]), [1, 0, 4, 0, 0, 0, 0]);
+        let mut run = vec![1, 2];
+        run.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(call(&run), [1, 0, 0x3C, 0, 0, 0, 1, 2, 0, 0, 0, 0]);
+
+        let dump = call(&[1, 4]);
+        let mut s = String::new();
+        Machine::new(&demo_prog()).write_stack(&mut s).unwrap();
+        assert_eq!(&dump[..2], &[1, 0]);
+        assert_eq!(std::str::from_utf8(&dump[2..]).unwrap(), s);
+
+        let snap = call(&[1, 3]);
+        assert_eq!(&snap[2..6], &[0x3C, 0, 0, 0]);
+        assert_eq!(call(&[2, 1])[..2], [1, 1]); // bad version
+
+        // Programs that don't verify aren't loaded.
+        let bad = encode(&[Insn::Inh(Inherent::from_u32(9))]);
+        let obj = ObjectFile { entry: 0, code: bad, ..Default::default() };
+        let mut load = vec![1, 0];
+        load.extend_from_slice(&obj.to_bytes());
+        let resp = call(&load);
+        assert_eq!(&resp[..2], &[1, 1]);
+        assert_eq!(&resp[2..], b"#0000_0000: unknown inherent");
+
+        // A loop only runs for so long per request.
+        let spin = encode(&[Insn::Jump(JUMP_RELATIVE)]);
+        let obj = ObjectFile { entry: 0, code: spin, ..Default::default() };
+        let mut load = vec![1, 0];
+        load.extend_from_slice(&obj.to_bytes());
+        assert_eq!(call(&load), [1, 0]);
+        assert_eq!(call(&run), [1, 0, 0, 0, 0, 0, 0]);
+
+        drop(c);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn checked_constructors() {
+        assert_eq!(ItemId::new(0x1FFF_FFFF).unwrap().get(), 0x1FFF_FFFF);
+        assert!(ItemId::new(0x2000_0000).is_err());
+        assert_eq!(ObjPtr::new(8).unwrap().addr(), 8);
+        assert!(ObjPtr::new(0).is_err());
+        assert!(ObjPtr::new(6).is_err());
+        assert!(ObjPtr::new(0xFFFF_FFF0).is_err());
+    }
+
+    #[test]
+    fn decoder() {
+        let insns = [Insn::Xlo(3), Insn::Inh(Inherent::Pop), Insn::Def(0)];
+        let code = encode(&insns);
+        let decoded: Result<Vec<_>, _> = InsnDecoder::new(&code).collect();
+        assert_eq!(decoded.unwrap(), insns);
+
+        let mut bytes: Vec<u8> =
+            code.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+        bytes.truncate(10);
+        let decoded: Vec<_> = InsnDecoder::from_bytes(&bytes).collect();
+        assert_eq!(decoded, vec![
+            Ok(insns[0]),
+            Ok(insns[1]),
+            Err(DecodeError::PartialWord { offset: 8, len: 2 }),
+        ]);
+        assert_eq!(InsnDecoder::from_bytes(&[]).next(), None);
+    }
+
+    #[test]
+    fn stack_format_styles() {
+        let m = Machine::new(&encode(&[Insn::Val(0)]));
+        let dump = |style| {
+            let mut s = String::new();
+            m.write_stack_with(&mut s, style).unwrap();
+            s
+        };
+        let mut s = String::new();
+        m.write_stack(&mut s).unwrap();
+        assert_eq!(s, dump(FormatStyle::Grouped));
+        assert!(s.starts_with("#0000_0004:\n  cap  = #0000_0000\n"));
+        assert!(dump(FormatStyle::Hex).starts_with("0x4:\n  cap  = 0x0\n"));
+        assert!(dump(FormatStyle::Decimal).starts_with("4:\n  cap  = 0\n"));
+    }
+
+    #[test]
+    fn stack_colors() {
+        let mut m = Machine::new(&encode(&[Insn::Xlo(0), Insn::Push(0)]));
+        m.step().unwrap();
+        m.step().unwrap();
+        let mut plain = String::new();
+        m.write_stack(&mut plain).unwrap();
+        let mut colored = String::new();
+        m.write_stack_colored(&mut colored, FormatStyle::Grouped).unwrap();
+        assert_ne!(plain, colored);
+        let head = format!("#{}:", friendly_hex_u32(m.fp.addr()));
+        assert!(colored.starts_with(&format!("\x1B[1;32m{}\x1B[0m\n", head)));
+
+        let mut stripped = String::new();
+        let mut in_escape = false;
+        for c in colored.chars() {
+            match c {
+                '\x1B' => in_escape = true,
+                'm' if in_escape => in_escape = false,
+                _ if in_escape => (),
+                _ => stripped.push(c),
+            }
+        }
+        assert_eq!(stripped, plain);
+    }
+
+    #[test]
+    fn code_is_read_only() {
+        // Words 2 through 8 double as an object at 8 with room for one I32.
+        let mut code = encode(&[Insn::Xlo(5), Insn::Def(1), Insn::Def(8)]);
+        code.resize(9, 0);
+        let mut m = Machine::builder(&code).strict(true).build();
+        assert_eq!(m.code_end(), 36);
+        m.step().unwrap();
+        m.fp = ObjPtr::at(8);
+        match m.step() {
+            Err(Fault { kind: InsnException::WriteToCode(28), .. }) => (),
+            r => panic!("expected WriteToCode(28), got {:?}", r),
+        }
+        let fresh = Machine::new(&code);
+        assert_eq!(m.mem.as_bytes()[..36], fresh.mem.as_bytes()[..36]);
+
+        let build = |policy| {
+            let mut m = Machine::builder(&code).code_writes(policy).build();
+            m.step().unwrap();
+            m.fp = ObjPtr::at(8);
+            let r = m.step();
+            (m, r)
+        };
+        match build(CodeWritePolicy::Trap).1 {
+            Err(Fault { kind: InsnException::SelfModifyingCode(28), .. }) => (),
+            r => panic!("expected SelfModifyingCode(28), got {:?}", r),
+        }
+        let (m, r) = build(CodeWritePolicy::Log);
+        r.unwrap();
+        assert_eq!(m.code_writes(), &[(4, 28), (4, 32)]);
+        assert_eq!(m.mem.load_u32(32), 5);
+        let (m, r) = build(CodeWritePolicy::Allow);
+        r.unwrap();
+        assert!(m.code_writes().is_empty());
+    }
+
+    #[test]
+    fn relocation() {
+        // Words 1 and 3 hold code addresses; word 2 only looks like one.
+        let code = encode(&[
+            Insn::Val(0), Insn::Xlo(0xC), Insn::Xlo(0xC), Insn::Jump(0x4),
+        ]);
+        let obj = ObjectFile {
+            entry: 4, code, relocs: vec![1, 3], ..Default::default()
+        };
+        let b = obj.to_bytes();
+        assert_eq!(b[3], 0x81);
+        let loaded = ObjectFile::from_bytes(&b).unwrap();
+        assert_eq!(loaded.relocs, vec![1, 3]);
+        assert_eq!(loaded.code, obj.code);
+
+        let m = Machine::load_at(&loaded, 0x100).unwrap();
+        assert_eq!(m.pc, 0x104);
+        assert_eq!(m.code_end(), 0x110);
+        let insns: Vec<_> = InsnDecoder::new(&[
+            m.mem.load_u32(0x104), m.mem.load_u32(0x108),
+            m.mem.load_u32(0x10C),
+        ]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            insns, [Insn::Xlo(0x10C), Insn::Xlo(0xC), Insn::Jump(0x104)]);
+
+        let bad = ObjectFile {
+            entry: 0, code: vec![0], relocs: vec![1], ..Default::default()
+        };
+        match bad.validate() {
+            Err(LoadError::BadRelocation(1)) => (),
+            r => panic!("expected BadRelocation(1), got {:?}", r),
+        }
+        let mut far = ObjectFile {
+            entry: 0, code: encode(&[Insn::Xlo(0x1FFF_FFFC)]), relocs: vec![0],
+            ..Default::default()
+        };
+        match far.relocate(4) {
+            Err(LoadError::BadRelocation(0)) => (),
+            r => panic!("expected BadRelocation(0), got {:?}", r),
+        }
+
+        // A jump target can't carry into the flags, and relative jumps
+        // stay put.
+        let jumps = encode(&[
+            Insn::Jump(JUMP_IF_ZERO | 0x4), Insn::Jump(JUMP_RELATIVE | 0x4),
+        ]);
+        let mut obj = ObjectFile {
+            entry: 0, code: jumps, relocs: vec![0, 1], ..Default::default()
+        };
+        obj.relocate(0x100).unwrap();
+        assert_eq!(obj.code, encode(&[
+            Insn::Jump(JUMP_IF_ZERO | 0x104), Insn::Jump(JUMP_RELATIVE | 0x4),
+        ]));
+        let mut far = ObjectFile {
+            entry: 0, code: encode(&[Insn::Jump(0x4)]), relocs: vec![0],
+            ..Default::default()
+        };
+        match far.relocate(JUMP_RELATIVE - 4) {
+            Err(LoadError::BadRelocation(0)) => (),
+            r => panic!("expected BadRelocation(0), got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn link_modules() {
+        use link::{link, LinkError, Module};
+
+        let main = Module {
+            code: vec![Insn::Xlo(0), Insn::Jump(0x4), Insn::Def(0)],
+            relocs: vec![1],
+            globals: vec![("main".to_string(), 0)],
+            externs: vec![(0, "helper".to_string())],
+        };
+        let lib = Module {
+            code: vec![Insn::Val(0), Insn::Jump(0)],
+            relocs: vec![1],
+            globals: vec![("helper".to_string(), 1)],
+            externs: vec![],
+        };
+        let (code, symbols) = link(&[main.clone(), lib.clone()]).unwrap();
+        assert_eq!(code, encode(&[
+            Insn::Xlo(0x10), Insn::Jump(0x4), Insn::Def(0),
+            Insn::Val(0), Insn::Jump(0xC),
+        ]));
+        assert_eq!(symbols["main"], 0);
+        assert_eq!(symbols["helper"], 0x10);
+
+        let far = Module {
+            code: vec![Insn::Jump(JUMP_TARGET_MASK & !3)],
+            externs: vec![(0, "helper".to_string())],
+            ..Module::default()
+        };
+        assert_eq!(
+            link(&[far, lib.clone()]).unwrap_err(),
+            LinkError::BadInsn {
+                module: 0, index: 0,
+                msg: "jump target doesn't fit in bits 26..0",
+            });
+
+        assert_eq!(
+            link(&[main.clone(), lib.clone(), lib]).unwrap_err(),
+            LinkError::DuplicateSymbol("helper".to_string()));
+        assert_eq!(
+            link(&[main]).unwrap_err(),
+            LinkError::UndefinedSymbol("helper".to_string()));
+    }
+
+    #[test]
+    fn bench_workloads_halt() {
+        for w in &[
+            bench::tight_loop(100),
+            bench::deep_recursion(100),
+            bench::alloc_heavy(100),
+        ] {
+            match w.run_with_limit(w.steps()) {
+                Ok(StopReason::Halted(_)) => (),
+                r => panic!("{} didn't halt: {:?}", w.name, r),
+            }
+            match w.run_with_limit(w.steps() - 1) {
+                Ok(StopReason::StepLimit) => (),
+                r => panic!("{} halted early: {:?}", w.name, r),
+            }
+        }
+        let mut m = bench::deep_recursion(100).machine();
+        m.run_while(|_| true, u64::MAX).unwrap();
+        assert_eq!(m.max_frame_depth(), 100);
+    }
+
+    #[test]
+    fn mem_grows_ahead_and_rezeroes() {
+        let mut mem = Mem::from_vec(vec![1; 8]);
+        mem.set_len(12);
+        assert_eq!(mem.len(), 12);
+        assert_eq!(mem.bytes.len(), 16);
+        assert_eq!(mem.as_bytes()[8..], [0; 4]);
+        assert!(mem.try_load_u32(12).is_err());
+
+        mem.store_u32(8, !0);
+        mem.set_len(8);
+        mem.set_len(16);
+        assert_eq!(mem.bytes.len(), 16);
+        assert_eq!(mem.load_u32(8), 0);
+    }
+
+    #[test]
+    fn predecode() {
+        let code = demo_prog();
+        let mut m = Machine::builder(&code).predecode(true).build();
+        assert_eq!(m.decoded.as_ref().unwrap().len(), code.len());
+        let x = run_checked(&mut m).unwrap();
+        assert_eq!(x.ty(), Type::I32);
+        assert_eq!(m.pc, 4*(code.len() as u32 - 1));
+
+        let m = Machine::builder(&code)
+            .predecode(true)
+            .code_writes(CodeWritePolicy::Allow)
+            .build();
+        assert!(m.decoded.is_none());
+    }
+
+    #[test]
+    fn machine_pool() {
+        let mut pool = MachinePool::new();
+        for _ in 0..3 {
+            match pool.run(&demo_prog()) {
+                Ok(StopReason::Halted(XData::I32(0))) => (),
+                r => panic!("expected to halt with 0, got {:?}", r),
+            }
+            assert_eq!(pool.idle(), 1);
+        }
+        let m = pool.machine(&demo_prog());
+        assert_eq!(pool.idle(), 0);
+        assert_eq!(m.mem.as_bytes(), Machine::new(&demo_prog()).mem.as_bytes());
+        assert!(m.mem.bytes.capacity() > m.mem.as_bytes().len());
+    }
+
+    #[test]
+    fn huge_header_fields_overflow_cleanly() {
+        let mut m = Machine::builder(&encode(&[Insn::Xlo(0), Insn::Def(1)]))
+            .strict(true)
+            .build();
+        m.step().unwrap();
+        m.fp.set_size(&mut m.mem, 0xFFFF_FFF0);
+        match m.find_in_frame(m.fp, ItemId(1)) {
+            Err(InsnException::SizeOverflow) => (),
+            r => panic!("expected SizeOverflow, got {:?}", r),
+        }
+        m.fp.set_size(&mut m.mem, 0);
+        m.fp.set_cap(&mut m.mem, 0xFFFF_FFF0);
+        match m.is_top_frame() {
+            Err(InsnException::SizeOverflow) => (),
+            r => panic!("expected SizeOverflow, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn from_reader() {
+        let code = demo_prog();
+        let bytes: Vec<u8> =
+            code.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+        let m = Machine::from_reader(&mut &bytes[..], bytes.len() as u64)
+            .unwrap();
+        assert_eq!(m.mem.as_bytes(), Machine::new(&code).mem.as_bytes());
+
+        match Machine::from_reader(&mut &bytes[..], 6) {
+            Err(LoadError::UnalignedCode) => (),
+            r => panic!("expected UnalignedCode, got {:?}", r.map(|_| ())),
+        }
+        match Machine::from_reader(&mut &bytes[..6], 8) {
+            Err(LoadError::Truncated) => (),
+            r => panic!("expected Truncated, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn peaks_and_reset() {
+        let mut m = Machine::new(&demo_prog());
+        let start_mem = m.max_mem();
+        run_checked(&mut m).unwrap();
+        assert_eq!(m.frame_depth(), 0);
+        assert_eq!(m.max_frame_depth(), 3);
+        assert!(m.max_mem() > start_mem);
+
+        m.reset();
+        assert_eq!(m.max_frame_depth(), 0);
+        assert_eq!(m.max_mem(), start_mem);
+        run_checked(&mut m).unwrap();
+        assert_eq!(m.max_frame_depth(), 3);
+    }
+
+    #[test]
+    fn demo_events() {
+        let mut m = Machine::new(&demo_prog());
+        let mut events = Vec::new();
+        while m.step_events(&mut |e| events.push(e)).unwrap().is_none() {}
+        let summary: Vec<_> = events.iter().map(|e| match e {
+            Event::FramePushed(_) => "push",
+            Event::FramePopped => "pop",
+            Event::ItemDefined(..) => "def",
+            Event::Jumped(_) => "jump",
+            Event::Halted(_) => "halt",
+        }).collect();
+        assert_eq!(summary, [
+            "push", "push", "def", "def", "def", "def", "push",
+            "pop", "pop", "pop", "halt",
+        ]);
+    }
+
+    #[test]
+    fn frame_callbacks() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut m = Machine::new(&demo_prog());
+        let l = log.clone();
+        m.on_frame_push(move |_, depth| l.borrow_mut().push(depth as i32));
+        let l = log.clone();
+        m.on_frame_pop(move |_, depth| l.borrow_mut().push(-(depth as i32)));
+        run_checked(&mut m).unwrap();
+        assert_eq!(*log.borrow(), [1, 2, 3, -2, -1, 0]);
+    }
+
+    #[test]
+    fn random_is_reproducible() {
+        let draw = |m: &mut Machine| -> Vec<u32> {
+            (0..4).map(|_| {
+                m.call_builtin(BUILTIN_RANDOM).unwrap();
+                match m.x {
+                    XData::I32(n) => n,
+                    x => panic!("expected I32, got {:?}", x),
+                }
+            }).collect()
+        };
+        let mut a = Machine::builder(&[]).seed(7).build();
+        let mut b = Machine::builder(&[]).seed(7).build();
+        let first = draw(&mut a);
+        assert_eq!(first, draw(&mut b));
+        assert_ne!(first, draw(&mut Machine::builder(&[]).seed(8).build()));
+        a.reset();
+        assert_eq!(first, draw(&mut a));
+    }
+
+    #[test]
+    fn clock() {
+        let mut m = Machine::builder(&demo_prog()).deterministic(true).build();
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        m.call_builtin(BUILTIN_CLOCK).unwrap();
+        assert!(matches!(m.x, XData::I32(3)));
+
+        let mut m = Machine::builder(&[]).allow_clock(false).build();
+        match m.call_builtin(BUILTIN_CLOCK) {
+            Err(InsnException::CapabilityDenied(BUILTIN_CLOCK)) => (),
+            r => panic!("expected CapabilityDenied, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn run_to_pc() {
+        let mut m = Machine::new(&demo_prog());
+        assert!(matches!(m.run_to(4*6, 100), Ok(StopReason::ReachedPc)));
+        assert_eq!(m.pc, 4*6);
+        assert!(matches!(m.run_to(4*6, 100), Ok(StopReason::ReachedPc)));
+        assert!(matches!(m.run_to(4*12, 2), Ok(StopReason::StepLimit)));
+        assert!(matches!(m.run_to(0, 100), Ok(StopReason::Halted(_))));
+    }
+
+    #[test]
+    fn run_until_item_defined() {
+        let mut m = Machine::new(&demo_prog());
+        match m.run_until_defined(ItemId(3), 100) {
+            Ok(StopReason::Defined(_)) => (),
+            r => panic!("expected Defined, got {:?}", r),
+        }
+        assert_eq!(m.pc, 4*9);
+        assert!(matches!(
+            m.run_until_defined(ItemId(5), 100), Ok(StopReason::Halted(_))));
+    }
+
+    #[test]
+    fn run_while_pred() {
+        let mut m = Machine::new(&demo_prog());
+        let fp = m.fp;
+        assert!(matches!(
+            m.run_while(|m| m.fp == fp, 100), Ok(StopReason::Predicate)));
+        assert_eq!(m.pc, 4*3);
+        assert!(matches!(
+            m.run_while(|_| true, 100), Ok(StopReason::Halted(_))));
+    }
+
+    #[test]
+    fn peek_matches_step() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut m = Machine::new(&demo_prog());
+        let steps = Rc::new(Cell::new(0));
+        let s = steps.clone();
+        m.on_step(move |_| {
+            s.set(s.get() + 1);
+            ControlFlow::Continue(())
+        });
+        loop {
+            let before = m.mem.as_bytes().to_vec();
+            let effect = m.peek_step().unwrap();
+            assert_eq!(m.mem.as_bytes(), &before[..]);
+            // The peek's clone has no callbacks.
+            assert_eq!(steps.get(), m.steps);
+
+            let r = m.step().unwrap();
+            assert_eq!(effect.pc, m.pc);
+            assert_eq!(effect.fp, m.fp);
+            assert_eq!(effect.mem_len, m.tos());
+            for &(addr, _, new) in &effect.writes {
+                assert_eq!(m.load_u32(addr), new);
+            }
+            if r.is_some() {
+                assert!(effect.halted.is_some());
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn failed_pop_changes_nothing() {
+        let code = encode(&[
+            Insn::Xlo(0), Insn::Push(1), Insn::Inh(Inherent::Pop),
+        ]);
+        let mut m = Machine::builder(&code).strict(true).build();
+        m.step().unwrap();
+        m.step().unwrap();
+        // A member object that says it's a call frame.
+        m.fp.set_kind(&mut m.mem, FrameKind::Call);
+        let before = (m.pc, m.fp, m.frame_depth(), m.tos());
+        match m.step() {
+            Err(Fault { kind: InsnException::CorruptState(_), .. }) => (),
+            r => panic!("expected CorruptState, got {:?}", r),
+        }
+        assert_eq!((m.pc, m.fp, m.frame_depth(), m.tos()), before);
+    }
+
+    #[test]
+    fn strict_mode_doesnt_panic() {
+        let prog = encode(&[
+            Insn::Xlo(5), Insn::Def(1), Insn::Val(1), Insn::Push(0),
+        ]);
+
+        let mut m = Machine::builder(&prog).strict(true).build();
+        m.step().unwrap();
+        m.step().unwrap();
+        // Scribble over the new item's type bits.
+        let item = m.find_in_frame(m.fp, ItemId(1)).unwrap().unwrap();
+        m.mem.store_u32(item.0, 0xE000_0001);
+        match m.step() {
+            Err(Fault { kind: InsnException::CorruptState(_), .. }) => (),
+            r => panic!("expected CorruptState, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn unknown_inherent_faults() {
+        let prog = encode(&[Insn::Inh(Inherent::from_u32(9))]);
+        for &strict in [false, true].iter() {
+            let mut m = Machine::builder(&prog).strict(strict).build();
+            match m.step() {
+                Err(Fault { kind: InsnException::Unimplemented(_), .. }) => (),
+                r => panic!("expected Unimplemented, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn find_stops_on_prev_cycle() {
+        let code = encode(&[
+            Insn::Xlo(0), Insn::Push(0), Insn::Push(0), Insn::Push(0),
+        ]);
+        let mut m = Machine::new(&code);
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        // Neither frame in the loop is the global frame, so the walk never
+        // gets there.
+        let below = m.fp.prev(&m.mem).unwrap();
+        below.set_prev(&mut m.mem, Some(m.fp));
+        match m.find(ItemId(1)) {
+            Err(InsnException::CorruptFrame(_, "cycle in `prev` chain")) => (),
+            r => panic!("expected CorruptFrame, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn session_reports_corrupt_items() {
+        let code = encode(&[Insn::Xlo(5), Insn::Def(1), Insn::Def(0)]);
+        let mut s = Session::new(&code);
+        assert!(!s.run(2).unwrap());
+        assert_eq!(s.globals().unwrap(), vec![(ItemId(1), Value::I32(5))]);
+        let (p, _, _) = s.m.gp.iter_items(&s.m.mem).next().unwrap().unwrap();
+        s.m.mem.store_u32(p.0, 7 << 29 | 1);
+        assert!(s.globals().is_err());
+        assert!(s.stack_dump().is_err());
+    }
+
+    #[test]
+    fn prev_cycle_is_reported() {
+        let mut m = Machine::new(&demo_prog());
+        m.fp.set_prev(&mut m.mem, Some(m.fp));
+        let rule = "cycle in `prev` chain";
+        assert_eq!(m.check_invariants(), Err(IntegrityError {
+            frame: None, rule,
+        }));
+    }
+
+    #[test]
+    fn default_machine_faults_cleanly() {
+        let mut m = Machine::default();
+        match m.step() {
+            Err(Fault { pc: 4, kind: InsnException::PcOutOfCode(4) }) => (),
+            r => panic!("expected PcOutOfCode, got {:?}", r),
+        }
+        m.reset();
+        assert_eq!(m.pc, 4);
+        assert_eq!(m.fp, m.gp);
+
+        let mut m = Machine::builder(&[]).separate_code(true).build();
+        assert!(matches!(
+            m.step(),
+            Err(Fault { kind: InsnException::PcOutOfCode(_), .. }),
+        ));
+    }
+
+    #[test]
+    fn frame_kinds() {
+        let prog = demo_prog();
+        let mut m = Machine::builder(&prog).strict(true).build();
+        assert_eq!(m.fp.kind(&m.mem), FrameKind::Call);
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        let call = m.fp;
+        assert_eq!(call.kind(&m.mem), FrameKind::Call);
+        m.step().unwrap();
+        let obj = m.fp;
+        assert_eq!(obj.kind(&m.mem), FrameKind::Object);
+        assert_eq!(obj.cap(&m.mem), 0);
+
+        // Growing the object keeps its kind.
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        assert_eq!(obj.kind(&m.mem), FrameKind::Object);
+        assert_eq!(obj.cap(&m.mem), 16);
+
+        obj.set_kind(&mut m.mem, FrameKind::Call);
+        m.pc = 4*13;
+        match m.step() {
+            Err(Fault { kind: InsnException::CorruptState(_), .. }) => (),
+            r => panic!("expected CorruptState, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn failed_object_push_leaves_frame() {
+        let prog = encode(&[
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Xlo(0x1FFF_FFF0),
+            Insn::Xhi(0x7),
+            Insn::Push(1),
+        ]);
+        let mut m = Machine::new(&prog);
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        let (fp, len) = (m.fp, m.mem.len());
+        match m.step() {
+            Err(Fault { kind: InsnException::SizeOverflow, .. }) => (),
+            r => panic!("expected SizeOverflow, got {:?}", r),
+        }
+        assert_eq!(m.fp, fp);
+        assert_eq!(m.mem.len(), len);
+        assert_eq!((fp.cap(&m.mem), fp.size(&m.mem)), (0, 0));
+        assert_eq!(m.pc, 4*4);
+    }
+
+    #[test]
+    fn register_accessors() {
+        let mut m = Machine::new(&demo_prog());
+        assert_eq!(m.frame_pointer(), m.global_pointer());
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.pc(), 4*3);
+        assert_eq!(m.frame_pointer(), m.fp.addr());
+        assert_eq!(m.global_pointer(), m.code_end());
+        assert!(matches!(m.x(), XData::I32(0)));
+    }
+
+    #[test]
+    fn frame_inherent() {
+        let prog = encode(&[
+            Insn::Inh(Inherent::Frame),
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Inh(Inherent::Frame),
+        ]);
+        let mut m = Machine::new(&prog);
+        m.step().unwrap();
+        assert!(matches!(m.x(), XData::Object(p) if p == m.gp));
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        assert_ne!(m.fp, m.gp);
+        assert!(matches!(m.x(), XData::Object(p) if p == m.fp));
+    }
+
+    /// Assemble `src` and check that `verify` takes it.
+    fn verified(src: &str) -> Vec<u32> {
+        let prog = assemble(src).unwrap();
+        verify(&prog).unwrap();
+        prog
+    }
+
+    #[test]
+    fn pc_inherent() {
+        // Capture pc, jump away, and come back to it with `call`, which
+        // jumps to any Code value. Item 2 says which pass this is.
+        let prog = assemble("
+                    xlo 0
+                    def 2
+                    inh pc
+            back:   def 1       ; the captured address: `back`
+                    val 2
+                    jumpz away
+                    xlo 7
+                    def 0       ; return from the call
+            away:   xlo 1
+                    set 2
+                    val 1
+                    inh call
+                    def 0
+        ").unwrap();
+        let mut m = Machine::new(&prog);
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        assert!(matches!(m.x(), XData::Code(12)));
+        assert_eq!(m.pc, 12);
+
+        let mut backs = 0;
+        let x = loop {
+            backs += (m.pc == 12) as u32;
+            if let Some(x) = m.step().unwrap() {
+                break x;
+            }
+        };
+        assert_eq!(backs, 2);
+        assert_eq!(m.max_frame_depth(), 1);
+        assert!(matches!(x, XData::I32(7)));
+    }
+
+    #[test]
+    fn xhi_immediate_checked_at_run_time() {
+        // Xhi with bit 3 of the immediate set, which `as_u32` won't encode.
+        let prog = [(7 << 29) | 0x8];
+        let mut m = Machine::new(&prog);
+        match m.step() {
+            Err(Fault {
+                pc: 0,
+                kind: InsnException::BadImmediate(Insn::Xhi(0x8)),
+            }) => (),
+            r => panic!("expected BadImmediate, got {:?}", r),
+        }
+        assert_eq!(m.pc, 0);
+    }
+
+    #[test]
+    fn locals_shadow_globals() {
+        let prog = encode(&[
+            Insn::Xlo(5),
+            Insn::Def(1),
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Val(1),
+            Insn::Val(1),
+            Insn::Xlo(7),
+            Insn::Def(1),
+            Insn::Val(1),
+            Insn::Inh(Inherent::Pop),
+            Insn::Val(1),
+        ]);
+        let mut m = Machine::new(&prog);
+        let mut xs = Vec::new();
+        while (m.pc as usize) < 4*prog.len() {
+            m.step().unwrap();
+            if let XData::I32(n) = m.x() {
+                xs.push(n);
+            }
+        }
+        // The second read of the global comes from the cache, and the local
+        // definition has to invalidate it.
+        assert_eq!(&xs[6..], &[5, 5, 7, 7, 7, 7, 5]);
+    }
+
+    #[test]
+    fn scope_modes_resolve_differently() {
+        let prog = encode(&[
+            Insn::Xlo(5),
+            Insn::Def(1),
+            // A plain frame holding object 2, which defines its own 1.
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Xlo(0),
+            Insn::Push(2),
+            Insn::Xlo(9),
+            Insn::Def(1),
+            Insn::Inh(Inherent::Pop),
+            // The "closure": called from the plain frame, but defined in
+            // object 2.
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Val(1),
+        ]);
+        let run = |mode| {
+            let mut m = Machine::builder(&prog).scope_mode(mode).build();
+            assert_eq!(m.scope_mode(), mode);
+            for _ in 0..prog.len() - 1 {
+                m.step().unwrap();
+            }
+            let caller = m.fp.prev(&m.mem).unwrap();
+            let env = m.find_in_frame(caller, ItemId(2)).unwrap().unwrap();
+            m.fp.set_base(&mut m.mem, Some(ObjPtr::at(env.0 + 4)));
+            let seen = m.value_of(ItemId(1));
+            assert!(matches!(m.value_of(ItemId(0)),
+                Some(XData::Object(p)) if p == m.gp));
+            m.step().unwrap();
+            assert_eq!(format!("{:?}", seen), format!("{:?}", Some(m.x())));
+            match m.x() {
+                XData::I32(n) => n,
+                x => panic!("expected I32, got {:?}", x),
+            }
+        };
+        assert_eq!(run(ScopeMode::Dynamic), 5);
+        assert_eq!(run(ScopeMode::Lexical), 9);
+    }
+
+    #[test]
+    fn reserve_grows_once() {
+        let mut insns = Vec::new();
+        for id in 1..=8 {
+            insns.push(Insn::Xlo(id));
+            insns.push(Insn::Def(id));
+        }
+        let mut m = Machine::new(&encode(&insns));
+        m.reserve(64).unwrap();
+        let len = m.mem.len();
+        assert_eq!(m.fp.cap(&m.mem), 64);
+        for _ in 0..insns.len() {
+            m.step().unwrap();
+        }
+        assert_eq!(m.mem.len(), len);
+        assert_eq!((m.fp.cap(&m.mem), m.fp.size(&m.mem)), (64, 64));
+
+        // Reserving what's already there is a no-op.
+        m.reserve(0).unwrap();
+        assert!(matches!(m.reserve(2), Err(InsnException::UnalignedCap)));
+    }
+
+    #[test]
+    fn xdata_accessors() {
+        let x = XData::I32(0xFFFF_FFFF);
+        assert_eq!(x.as_i32(), Some(-1));
+        assert_eq!(x.as_code(), None);
+        let p = ObjPtr::at(8);
+        assert_eq!(XData::Object(p).as_object(), Some(p));
+        assert_eq!(XData::Object(p).as_i32(), None);
+        assert_eq!(XData::Code(12).as_code(), Some(12));
+        assert_eq!(XData::BuiltinCode(1).as_builtin(), Some(1));
+        assert_eq!(XData::BuiltinCode(1).as_object(), None);
+    }
+
+    #[test]
+    fn verify_program() {
+        assert_eq!(verify(&demo_prog()), Ok(()));
+        let prog = encode(&[Insn::Xlo(1), Insn::Jump(0), Insn::Jump(4)]);
+        assert_eq!(verify(&prog), Ok(()));
+
+        let bad_xhi = [Insn::Xlo(1).as_u32(), (7 << 29) | 0x8];
+        assert_eq!(verify(&bad_xhi).map_err(|e| e.addr), Err(4));
+        let unaligned = encode(&[Insn::Xlo(1), Insn::Jump(2)]);
+        assert_eq!(verify(&unaligned).map_err(|e| e.addr), Err(4));
+        let past_end = encode(&[Insn::Jump(8), Insn::Xlo(1)]);
+        assert_eq!(verify(&past_end).map_err(|e| e.addr), Err(0));
+        let back = JUMP_RELATIVE | (-8i32 as u32 & JUMP_TARGET_MASK);
+        let before_start = encode(&[Insn::Xlo(1), Insn::Jump(back)]);
+        assert_eq!(verify(&before_start).map_err(|e| e.addr), Err(4));
+        let unknown = encode(&[Insn::Xlo(1), Insn::Inh(Inherent::from_u32(9))]);
+        assert_eq!(verify(&unknown), Err(VerifyError {
+            addr: 4,
+            why: "unknown inherent",
+        }));
+    }
+
+    #[test]
+    fn frame_containing() {
+        let prog = demo_prog();
+        let mut m = Machine::new(&prog);
+        for _ in 0..8 {
+            m.step().unwrap();
+        }
+        let frames: Vec<_> = m.frames().collect();
+        assert_eq!(frames.len(), 3);
+        let (obj, call, root) = (frames[0], frames[1], frames[2]);
+        assert_eq!(root, m.gp);
+
+        assert_eq!(m.frame_containing(0), None);
+        assert_eq!(m.frame_containing(root.addr()), Some(root));
+        assert_eq!(m.frame_containing(call.addr() + 4), Some(call));
+        // The object is an item in the call frame's body, but it's the
+        // innermost frame there.
+        assert_eq!(m.frame_containing(obj.addr() - 4), Some(call));
+        assert_eq!(m.frame_containing(obj.addr()), Some(obj));
+        assert_eq!(m.frame_containing(m.mem.len() - 4), Some(obj));
+        assert_eq!(m.frame_containing(m.mem.len()), None);
+    }
+
+    #[test]
+    fn item_containing() {
+        let prog = encode(&[
+            Insn::Xlo(5),
+            Insn::Def(1),
+            Insn::Xlo(16),
+            Insn::Push(2),
+            Insn::Xlo(7),
+            Insn::Def(3),
+            Insn::Inh(Inherent::Pop),
+        ]);
+        let mut m = Machine::new(&prog);
+        for _ in 0..7 {
+            m.step().unwrap();
+        }
+        let root = m.gp;
+        let body = root.body_offset(0);
+        assert_eq!(m.item_containing(root.addr()), None);
+        let (f, item, id, ty) = m.item_containing(body + 4).unwrap();
+        assert_eq!((f, item.0, id, ty), (root, body, ItemId(1), Type::I32));
+
+        // Object 2 starts at `body + 12`, after its own item header.
+        let obj = ObjPtr::at(body + 12);
+        let (f, item, id, ty) = m.item_containing(body + 8).unwrap();
+        assert_eq!(
+            (f, item.0, id, ty), (root, body + 8, ItemId(2), Type::Object));
+        let (f, _, id, _) = m.item_containing(obj.addr() + 4).unwrap();
+        assert_eq!((f, id), (root, ItemId(2)));
+        let (f, item, id, ty) =
+            m.item_containing(obj.body_offset(4)).unwrap();
+        let first = obj.body_offset(0);
+        assert_eq!((f, item.0, id, ty), (obj, first, ItemId(3), Type::I32));
+        // Spare room at the end of the object.
+        let (f, _, id, _) = m.item_containing(obj.body_offset(12)).unwrap();
+        assert_eq!((f, id), (root, ItemId(2)));
+    }
+
+    #[test]
+    fn validate_and_run() {
+        match Machine::validate_and_run(&demo_prog(), 100) {
+            Ok(XData::I32(0)) => (),
+            r => panic!("expected to halt with 0, got {:?}", r),
+        }
+        match Machine::validate_and_run(&demo_prog(), 3) {
+            Err(LobError::StepLimit(3)) => (),
+            r => panic!("expected StepLimit, got {:?}", r),
+        }
+        let bad = encode(&[Insn::Jump(2)]);
+        match Machine::validate_and_run(&bad, 100) {
+            Err(LobError::Verify(VerifyError { addr: 0, .. })) => (),
+            r => panic!("expected Verify, got {:?}", r),
+        }
+        let faults = encode(&[Insn::Val(1)]);
+        match Machine::validate_and_run(&faults, 100) {
+            Err(LobError::Fault(Fault {
+                pc: 0,
+                kind: InsnException::ItemNotFound,
+            })) => (),
+            r => panic!("expected Fault, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn all_item_ids() {
+        let prog = demo_prog();
+        let mut m = Machine::new(&prog);
+        for _ in 0..10 {
+            m.step().unwrap();
+        }
+        let frames: Vec<_> = m.frames().collect();
+        let (obj, call) = (frames[0], frames[1]);
+        let ids: Vec<_> = m.all_item_ids().into_iter()
+            .map(|(f, id, ty)| (f, id.get(), ty))
+            .collect();
+        assert_eq!(ids, vec![
+            (obj, 1, Type::I32),
+            (obj, 2, Type::I32),
+            (obj, 3, Type::I32),
+            (obj, 4, Type::I32),
+            (call, 1, Type::Object),
+        ]);
+    }
+
+    #[test]
+    fn write_log() {
+        let prog = encode(&[Insn::Xlo(5), Insn::Def(1), Insn::Xlo(6)]);
+        assert!(Machine::new(&prog).write_log().is_none());
+
+        let mut m = Machine::builder(&prog).log_writes(100).build();
+        m.step().unwrap();
+        m.step().unwrap();
+        let log = m.write_log().unwrap();
+        let body = m.fp.body_offset(0);
+        assert!(log.entries().contains(&(body, 0, item_header_to_u32(
+            Type::I32, ItemId(1)))));
+        assert!(log.entries().contains(&(body + 4, 0, 5)));
+        // The frame header's size field counts too.
+        assert_eq!(log.entries().last(), Some(&(m.fp.addr() + 4, 0, 8)));
+        assert!(!log.is_truncated());
+
+        let mut m = Machine::builder(&prog).log_writes(1).build();
+        m.step().unwrap();
+        m.step().unwrap();
+        let log = m.write_log().unwrap();
+        assert_eq!(log.entries().len(), 1);
+        assert!(log.is_truncated());
+        m.reset();
+        let log = m.write_log().unwrap();
+        assert!(log.entries().is_empty() && !log.is_truncated());
+    }
+
+    #[test]
+    fn jumps() {
+        let back = JUMP_RELATIVE | (-12i32 as u32 & JUMP_TARGET_MASK);
+        let prog = encode(&[
+            Insn::Jump(8),
+            Insn::Xlo(1),
+            // 8: skipped once X is 1.
+            Insn::Jump(JUMP_IF_ZERO | 16),
+            Insn::Def(0),
+            // 16: back to 4, which sets X to 1.
+            Insn::Jump(back),
+        ]);
+        let mut m = Machine::builder(&prog).strict(true).build();
+        let mut pcs = Vec::new();
+        let x = loop {
+            pcs.push(m.pc);
+            if let Some(x) = m.step().unwrap() {
+                break x;
+            }
+        };
+        assert_eq!(pcs, [0, 8, 16, 4, 8, 12]);
+        assert!(matches!(x, XData::I32(1)));
+
+        // Jumping to itself doesn't trip the "pc didn't advance" check.
+        let mut m = Machine::builder(&encode(&[Insn::Jump(JUMP_RELATIVE)]))
+            .strict(true)
+            .build();
+        m.step().unwrap();
+        assert_eq!(m.pc, 0);
+
+        // Nor does a call to itself, or a pop that returns to itself, in
+        // either mode.
+        for &strict in &[false, true] {
+            let prog = verified("push 2\ndef 1\ninh pc\ninh call");
+            let mut m = Machine::builder(&prog).strict(strict).build();
+            for _ in 0..4 {
+                m.step().unwrap();
+            }
+            assert_eq!((m.pc, m.frame_depth()), (12, 2));
+
+            // The callee ends at the `inh pop` after its own call.
+            let prog = verified("
+                        xlo 0
+                        def 2
+                        inh pc
+                        def 1
+                        val 2
+                        jumpz first
+                        jump popit
+                first:  xlo 1
+                        set 2
+                        val 1
+                        inh call
+                popit:  inh pop
+            ");
+            let mut m = Machine::builder(&prog).strict(strict).build();
+            for _ in 0..15 {
+                m.step().unwrap();
+            }
+            assert_eq!((m.pc, m.frame_depth()), (44, 0));
+            assert!(m.step().unwrap().is_some());
+        }
+
+        let bad = |insns: &[Insn]| {
+            let mut m = Machine::new(&encode(insns));
+            loop {
+                if let Err(Fault { kind, .. }) = m.step() {
+                    break kind;
+                }
+            }
+        };
+        assert!(matches!(
+            bad(&[Insn::Jump(8)]), InsnException::BadJumpTarget(8)));
+        assert!(matches!(
+            bad(&[Insn::Xlo(0), Insn::Jump(6)]),
+            InsnException::BadJumpTarget(6)));
+        assert!(matches!(
+            bad(&[Insn::Val(0), Insn::Jump(JUMP_IF_ZERO)]),
+            InsnException::WrongType));
+    }
+
+    #[test]
+    fn call_and_return() {
+        let prog = encode(&[
+            Insn::Inh(Inherent::Call),
+            Insn::Def(0),
+            // 8: the callee.
+            Insn::Xlo(42),
+            Insn::Inh(Inherent::Pop),
+        ]);
+        let mut m = Machine::builder(&prog).strict(true).build();
+        m.x = XData::Code(8);
+        m.step().unwrap();
+        assert_eq!((m.pc, m.frame_depth()), (8, 1));
+        assert_eq!(m.fp.ret(&m.mem).map(NonZeroU32::get), Some(4));
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!((m.pc, m.frame_depth()), (4, 0));
+        assert!(matches!(m.step(), Ok(Some(XData::I32(42)))));
+
+        let mut m = Machine::new(&prog);
+        m.x = XData::BuiltinCode(BUILTIN_RANDOM);
+        m.step().unwrap();
+        assert_eq!((m.pc, m.frame_depth()), (4, 0));
+        assert!(matches!(m.x(), XData::I32(_)));
+
+        let mut m = Machine::new(&prog);
+        match m.step() {
+            Err(Fault { kind: InsnException::WrongType, .. }) => (),
+            r => panic!("expected WrongType, got {:?}", r),
+        }
+        m.x = XData::Code(16);
+        match m.step() {
+            Err(Fault { kind: InsnException::BadJumpTarget(16), .. }) => (),
+            r => panic!("expected BadJumpTarget, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn alloc() {
+        let prog = encode(&[
+            Insn::Xlo(16),
+            Insn::Inh(Inherent::Alloc),
+            Insn::Inh(Inherent::Alloc),
+            Insn::Xlo(1),
+            Insn::Def(1),
+            Insn::Xlo(2),
+            Insn::Inh(Inherent::Alloc),
+        ]);
+        let mut m = Machine::builder(&prog).strict(true).build();
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.fp.cap(&m.mem), 32);
+        assert_eq!(m.fp.body_offset(32), m.tos());
+        let len = m.mem.len();
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.mem.len(), len);
+        assert_eq!(m.fp.size(&m.mem), 8);
+        m.check_invariants().unwrap();
+        m.step().unwrap();
+        match m.step() {
+            Err(Fault { kind: InsnException::UnalignedCap, .. }) => (),
+            r => panic!("expected UnalignedCap, got {:?}", r),
+        }
+
+        // A frame with another frame above it can't grow.
+        let prog = encode(&[
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Xlo(4),
+            Insn::Inh(Inherent::Alloc),
+        ]);
+        let mut m = Machine::new(&prog);
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        m.fp = m.gp;
+        match m.step() {
+            Err(Fault { kind: InsnException::NotTopFrame, .. }) => (),
+            r => panic!("expected NotTopFrame, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn set() {
+        let prog = encode(&[
+            Insn::Xlo(5),
+            Insn::Def(1),
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Xlo(6),
+            Insn::Set(1),
+            Insn::Inh(Inherent::Pop),
+            Insn::Val(1),
+            Insn::Set(2),
+        ]);
+        let mut m = Machine::builder(&prog).strict(true).build();
+        for _ in 0..8 {
+            m.step().unwrap();
+        }
+        assert!(matches!(m.x(), XData::I32(6)));
+        match m.step() {
+            Err(Fault { kind: InsnException::ItemNotFound, .. }) => (),
+            r => panic!("expected ItemNotFound, got {:?}", r),
+        }
+
+        m.pc = 4*5;
+        m.x = XData::Code(0);
+        match m.step() {
+            Err(Fault { kind: InsnException::WrongType, .. }) => (),
+            r => panic!("expected WrongType, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn def_every_type() {
+        let prog = encode(&[
+            Insn::Def(1),
+            Insn::Val(1),
+            Insn::Def(2),
+            Insn::Val(2),
+            Insn::Xlo(5),
+            Insn::Def(3),
+            Insn::Val(3),
+            Insn::Xlo(8),
+            Insn::Push(4),
+            Insn::Xlo(9),
+            Insn::Def(1),
+            Insn::Inh(Inherent::Pop),
+            Insn::Val(4),
+            Insn::Def(5),
+            Insn::Val(5),
+        ]);
+        let mut m = Machine::builder(&prog).strict(true).build();
+        // Set X by hand, since no instruction makes a BuiltinCode value.
+        m.x = XData::BuiltinCode(BUILTIN_RANDOM);
+        m.step().unwrap();
+        m.x = XData::I32(0);
+        m.step().unwrap();
+        assert!(matches!(m.x(), XData::BuiltinCode(BUILTIN_RANDOM)));
+        m.x = XData::Code(8);
+        m.step().unwrap();
+        m.x = XData::I32(0);
+        m.step().unwrap();
+        assert!(matches!(m.x(), XData::Code(8)));
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        assert!(matches!(m.x(), XData::I32(5)));
+
+        for _ in 0..8 {
+            m.step().unwrap();
+        }
+        let (orig, copy) = match (m.find(ItemId(4)), m.x()) {
+            (Ok(Some(item)), XData::Object(copy)) =>
+                (ObjPtr::at(item.0 + 4), copy),
+            r => panic!("expected object 4 and its copy, got {:?}", r),
+        };
+        assert_ne!(orig, copy);
+        assert_eq!(copy.kind(&m.mem), FrameKind::Object);
+        assert_eq!(copy.cap(&m.mem), orig.cap(&m.mem));
+        assert_eq!(copy.size(&m.mem), orig.size(&m.mem));
+        let inner = m.find_in_frame(copy, ItemId(1)).unwrap().unwrap();
+        assert_eq!(m.load_u32(inner.0 + 4), 9);
+        m.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn find_walks_outer_frames() {
+        let prog = encode(&[
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Xlo(7),
+            Insn::Def(1),
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Xlo(0),
+            Insn::Push(0),
+            Insn::Val(1),
+        ]);
+        let mut m = Machine::builder(&prog).strict(true).build();
+        for _ in 0..prog.len() {
+            m.step().unwrap();
+        }
+        assert!(matches!(m.x(), XData::I32(7)));
+    }
+
+    #[test]
+    fn inherent_round_trips() {
+        for n in 0..=10 {
+            assert_eq!(Inherent::from_u32(n).as_u32(), n);
+        }
+        let unknown = |n| UnknownInherent::new(n).map(Inherent::Unknown);
+        assert_eq!(Ok(Inherent::from_u32(9)), unknown(9));
+        assert_eq!(unknown(3), Err("inherent number is a known inherent"));
+        assert!(unknown(0x2000_0000).is_err());
+        let insn = Insn::Inh(unknown(0x1FFF_FFFF).unwrap());
+        assert_eq!(Insn::from_u32(insn.as_u32()), insn);
+    }
+
+    #[test]
+    fn checked_mem_access() {
+        let mut mem = Mem::from_vec(vec![0; 8]);
+        mem.try_store_u32(4, 7).unwrap();
+        assert!(matches!(mem.try_load_u32(4), Ok(7)));
+        for &addr in [5, 8, u32::MAX - 3, u32::MAX].iter() {
+            assert!(matches!(
+                mem.try_load_u32(addr),
+                Err(InsnException::OutOfBounds(a)) if a == addr));
+            assert!(matches!(
+                mem.try_store_u32(addr, 1),
+                Err(InsnException::OutOfBounds(a)) if a == addr));
+        }
+        assert_eq!(mem.as_bytes(), &[0, 0, 0, 0, 7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn disassemble_program() {
+        let code = [
+            Insn::Def(1).as_u32(),
+            Insn::Inh(Inherent::Pop).as_u32(),
+            Insn::Inh(Inherent::from_u32(9)).as_u32(),
+            Insn::Xlo(0x1FFF_FFFF).as_u32(),
+            Insn::Jump(JUMP_IF_ZERO | 0x10).as_u32(),
+            Insn::Jump(JUMP_RELATIVE | (JUMP_RELATIVE - 8)).as_u32(),
+            (7<<29) | 9,
+        ];
+        assert_eq!(disassemble(&code), "\
+#0000_0000  def 1
+#0000_0004  inh pop
+#0000_0008  inh 9
+#0000_000C  xlo 0x1FFF_FFFF
+#0000_0010  jumpz #0000_0010
+#0000_0014  jump -8
+#0000_0018  xhi 9  ; xhi immediate doesn't fit in bits 2..0
+");
+    }
+
+    #[test]
+    fn assemble_program() {
+        let src = "\
+            def 1      ; a comment
+            inh pop
+
+            #0000_0008 xlo 0x1FFF_FFFF
+            xhi 7
+            push #10
+            inh 9
+            jumpz #0000_0010
+            jump -8
+            jump +4
+        ";
+        let code = assemble(src).unwrap();
+        assert_eq!(code, [
+            Insn::Def(1).as_u32(),
+            Insn::Inh(Inherent::Pop).as_u32(),
+            Insn::Xlo(0x1FFF_FFFF).as_u32(),
+            Insn::Xhi(7).as_u32(),
+            Insn::Push(16).as_u32(),
+            Insn::Inh(Inherent::from_u32(9)).as_u32(),
+            Insn::Jump(JUMP_IF_ZERO | 0x10).as_u32(),
+            Insn::Jump(JUMP_RELATIVE | (JUMP_RELATIVE - 8)).as_u32(),
+            Insn::Jump(JUMP_RELATIVE | 4).as_u32(),
+        ]);
+        assert_eq!(assemble(&disassemble(&code)).unwrap(), code);
+
+        let err = |line, msg| Err(AssembleError { line, msg });
+        assert_eq!(assemble("def 1\nfrob 2"), err(2, "unknown mnemonic"));
+        assert_eq!(assemble("\n\ndef"), err(3, "missing operand"));
+        assert_eq!(assemble("xhi 8"),
+            err(1, "xhi immediate doesn't fit in bits 2..0"));
+        assert_eq!(assemble("xlo 0x2000_0000"),
+            err(1, "immediate doesn't fit in bits 28..0"));
+        assert_eq!(assemble("val 4294967296"),
+            err(1, "immediate isn't a number or is too big"));
+        assert_eq!(assemble("#0000_0004 def 1"),
+            err(1, "address doesn't match the code offset"));
+        assert_eq!(assemble("jump +0x400_0000"),
+            err(1, "jump offset doesn't fit in bits 26..0"));
+        assert_eq!(assemble("def 0\njump +3"),
+            err(2, "jump offset isn't a multiple of 4"));
+        assert_eq!(assemble("jumpz #6"),
+            err(1, "jump target isn't word-aligned"));
+        assert_eq!(assemble("l: l: xlo 1"),
+            err(1, "line has more than one label"));
+        assert_eq!(assemble("xlo 1 l:"),
+            err(1, "label isn't at the start of the line"));
+    }
+
+    #[test]
+    fn run_with_budget() {
+        let code = encode(&[Insn::Xlo(5), Insn::Def(0)]);
+        match Machine::new(&code).run(2) {
+            Ok((XData::I32(5), 2)) => (),
+            r => panic!("expected to halt with 5 after 2 steps, got {:?}", r),
+        }
+        for &limit in [0, 1].iter() {
+            match Machine::new(&code).run(limit) {
+                Err(RunError::StepLimitExceeded) => (),
+                r => panic!("expected StepLimitExceeded, got {:?}", r),
+            }
+        }
+        let spin = encode(&[Insn::Jump(0)]);
+        match Machine::new(&spin).run(1000) {
+            Err(RunError::StepLimitExceeded) => (),
+            r => panic!("expected StepLimitExceeded, got {:?}", r),
+        }
+        let faults = encode(&[Insn::Val(1)]);
+        match Machine::new(&faults).run(10) {
+            Err(RunError::Fault(Fault {
+                pc: 0,
+                kind: InsnException::ItemNotFound,
+            })) => (),
+            r => panic!("expected Fault, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn display_fault() {
+        let fault = Fault {
+            pc: 0x1C,
+            kind: InsnException::ItemExistsInFrame(ItemPtr(0x7B)),
+        };
+        assert_eq!(fault.to_string(),
+            "item already exists in frame at #0000_007B @ #0000_001C");
+        let kind = InsnException::Unimplemented(Insn::Push(0));
+        assert_eq!(kind.to_string(), "`push 0` isn't implemented");
+        assert_eq!(InsnException::StackUnderflow.to_string(),
+            "stack underflow");
+    }
+
+    #[test]
+    fn step_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut m = Machine::new(&demo_prog());
+        let l = log.clone();
+        m.on_step(move |s| {
+            l.borrow_mut().push((s.pc, s.insn));
+            if s.insn == Insn::Def(0) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        let pc = match m.run(100) {
+            Err(RunError::Fault(Fault {
+                pc,
+                kind: InsnException::Interrupted,
+            })) => pc,
+            r => panic!("expected Interrupted, got {:?}", r),
+        };
+        let log = log.borrow();
+        assert_eq!(log.len(), demo_prog().len());
+        assert_eq!(log[0], (0, Insn::Val(0)));
+        assert_eq!(*log.last().unwrap(), (pc, Insn::Def(0)));
+        assert_eq!(m.pc(), pc);
+    }
+
+    #[test]
+    fn iter_items() {
+        let code = encode(&[
+            Insn::Xlo(5), Insn::Def(1),
+            Insn::Xlo(0), Insn::Push(2),
+            Insn::Xlo(6), Insn::Def(3), Insn::Def(4),
+        ]);
+        let mut m = Machine::new(&code);
+        for _ in 0..7 {
+            m.step().unwrap();
+        }
+        let ids = |obj: ObjPtr, m: &Machine| -> Vec<(Type, ItemId)> {
+            obj.iter_items(&m.mem).map(|i| {
+                let (p, ty, id) = i.unwrap();
+                assert_eq!(m.find_in_frame(obj, id).unwrap(), Some(p));
+                (ty, id)
+            }).collect()
+        };
+        // The object has grown past its parent's size.
+        assert_eq!(ids(m.gp, &m), [
+            (Type::I32, ItemId(1)),
+            (Type::Object, ItemId(2)),
+        ]);
+        assert_eq!(ids(m.fp, &m), [
+            (Type::I32, ItemId(3)),
+            (Type::I32, ItemId(4)),
+        ]);
+
+        let item = m.find_in_frame(m.fp, ItemId(4)).unwrap().unwrap();
+        m.mem.store_u32(item.0, 0xE000_0004);
+        let mut items = m.fp.iter_items(&m.mem);
+        assert!(items.next().unwrap().is_ok());
+        assert!(matches!(items.next(),
+            Some(Err(InsnException::CorruptFrame(_, "bad item type")))));
+        assert!(items.next().is_none());
+    }
+
+    #[test]
+    fn mem_limit() {
+        // Push frames forever.
+        let code = encode(&[Insn::Xlo(8), Insn::Push(0), Insn::Jump(4)]);
+        let mut m = Machine::with_limit(&code, 0x100);
+        let (fp, tos) = loop {
+            let (fp, tos) = (m.fp, m.tos());
+            match m.step() {
+                Ok(None) => (),
+                Err(Fault { kind: InsnException::OutOfMemory, .. }) => {
+                    break (fp, tos);
+                },
+                r => panic!("expected OutOfMemory, got {:?}", r),
+            }
+        };
+        assert_eq!((m.fp, m.tos()), (fp, tos));
+        assert!(m.tos() <= 0x100);
+        assert!(m.tos() + OBJ_HEADER_SIZE + 8 > 0x100);
+
+        // The root frame is there regardless of the limit.
+        let code = encode(&[
+            Insn::Xlo(0), Insn::Push(0), Insn::Inh(Inherent::Pop),
+        ]);
+        let mut m = Machine::with_limit(&code, 0);
+        m.step().unwrap();
+        match m.step() {
+            Err(Fault { kind: InsnException::OutOfMemory, .. }) => (),
+            r => panic!("expected OutOfMemory, got {:?}", r),
+        }
+        m.pc = 8;
+        assert!(matches!(m.step(), Ok(Some(XData::I32(0)))));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let calc = |left: u32, op: Inherent, right: u32| {
+            let mut insns = Insn::load_u32(left).to_vec();
+            insns.push(Insn::Def(1));
+            insns.extend_from_slice(&Insn::load_u32(right));
+            insns.push(Insn::Inh(op));
+            let code = encode(&insns);
+            let mut m = Machine::new(&code);
+            for _ in 0..6 {
+                m.step().unwrap();
+            }
+            assert_eq!(m.size().unwrap(), 0);
+            match m.x {
+                XData::I32(n) => n,
+                x => panic!("expected I32, got {:?}", x),
+            }
+        };
+        assert_eq!(calc(2, Inherent::Add, 3), 5);
+        assert_eq!(calc(u32::MAX, Inherent::Add, 2), 1);
+        assert_eq!(calc(2, Inherent::Sub, 3), u32::MAX);
+        assert_eq!(calc(0x8000_0000, Inherent::Mul, 2), 0);
+        assert_eq!(calc(6, Inherent::Mul, 7), 42);
+        assert_eq!(calc(u32::MAX, Inherent::Cmp, 0), -1i32 as u32);
+        assert_eq!(calc(5, Inherent::Cmp, 5), 0);
+        assert_eq!(calc(5, Inherent::Cmp, 4), 1);
+
+        // Nothing to pop.
+        let code = encode(&[Insn::Xlo(1), Insn::Inh(Inherent::Add)]);
+        let mut m = Machine::new(&code);
+        m.step().unwrap();
+        match m.step() {
+            Err(Fault { kind: InsnException::ItemNotFound, .. }) => (),
+            r => panic!("expected ItemNotFound, got {:?}", r),
+        }
+        // Both operands have to be I32, and a fault leaves the item.
+        let code = encode(&[
+            Insn::Xlo(1), Insn::Def(1), Insn::Inh(Inherent::Frame),
+            Insn::Inh(Inherent::Sub),
+        ]);
+        let mut m = Machine::new(&code);
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        match m.step() {
+            Err(Fault { kind: InsnException::WrongType, .. }) => (),
+            r => panic!("expected WrongType, got {:?}", r),
+        }
+        assert_eq!(m.size().unwrap(), 8);
+    }
+
+    #[test]
+    fn huge_cap_overflows_cleanly() {
+        // A frame whose cap runs past the end of the address space can't
+        // have anything pushed on it.
+        let cases = [
+            (Insn::Push(0), XData::I32(0)),
+            (Insn::Push(1), XData::I32(0)),
+            (Insn::Inh(Inherent::Call), XData::Code(0)),
+            (Insn::Inh(Inherent::Alloc), XData::I32(4)),
+        ];
+        for &(insn, x) in cases.iter() {
+            let mut m = Machine::new(&encode(&[insn]));
+            m.x = x;
+            m.fp.set_cap(&mut m.mem, 0xFFFF_FFF0);
+            let (fp, tos) = (m.fp, m.tos());
+            match m.step() {
+                Err(Fault { kind: InsnException::SizeOverflow, .. }) => (),
+                r => panic!("{}: expected SizeOverflow, got {:?}", insn, r),
+            }
+            assert_eq!((m.fp, m.tos()), (fp, tos));
+            assert!(m.check_invariants().is_err());
+        }
+    }
+
+    #[test]
+    fn item_value() {
+        let code = encode(&[
+            Insn::Xlo(5), Insn::Def(1),
+            Insn::Inh(Inherent::Pc), Insn::Def(2),
+            Insn::Xlo(0), Insn::Push(3),
+        ]);
+        let mut m = Machine::new(&code);
+        for _ in 0..6 {
+            m.step().unwrap();
+        }
+        let obj = m.fp;
+        let item = |id| m.find(ItemId(id)).unwrap().unwrap();
+        assert_eq!(m.item_type(item(1)).unwrap(), Type::I32);
+        assert!(matches!(m.item_value(item(1)), XData::I32(5)));
+        assert!(matches!(m.item_value(item(2)), XData::Code(12)));
+        assert_eq!(m.item_type(item(3)).unwrap(), Type::Object);
+        assert!(matches!(m.item_value(item(3)), XData::Object(o) if o == obj));
+    }
+
+    #[test]
+    fn write_obj() {
+        assert_eq!(ItemId(0x1C).to_string(), "#0000_001C");
+        assert_eq!(format!("{:?}", ItemId(1)), "ItemId(#0000_0001)");
+
+        let code = encode(&[
+            Insn::Xlo(5), Insn::Def(1), Insn::Xlo(0), Insn::Push(0x1C),
+        ]);
+        let mut m = Machine::new(&code);
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        let mut s = String::new();
+        m.gp.write(&m.mem, &mut s).unwrap();
+        assert_eq!(s, "  id #0000_0001: I32\n  id #0000_001C: Object\n");
+        let mut s = String::new();
+        m.gp.write_with(&m.mem, &mut s, FormatStyle::Decimal).unwrap();
+        assert_eq!(s, "  id 1: I32\n  id 28: Object\n");
+
+        // Scribble over the second item's type bits.
+        let item = m.find_in_frame(m.gp, ItemId(0x1C)).unwrap().unwrap();
+        m.mem.store_u32(item.0, 0xE000_001C);
+        let mut s = String::new();
+        m.write_obj(&mut s, m.gp).unwrap();
+        assert!(s.starts_with("  id #0000_0001: I32\n  CorruptFrame("));
+    }
+
+    #[test]
+    fn load_u32() {
+        let values = [
+            0, 1, 0x1FFF_FFFF, 0x2000_0000, 0x3FFF_FFFF, 0xE000_0000,
+            0xFFFF_FFFF,
+        ];
+        for &value in values.iter() {
+            let code = encode(&Insn::load_u32(value));
+            assert_eq!(assemble(&format!("ldi {}", value)).unwrap(), code);
+            let mut m = Machine::new(&code);
+            m.step().unwrap();
+            m.step().unwrap();
+            assert!(matches!(m.x, XData::I32(x) if x == value));
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let code = encode(&[
+            Insn::Xlo(0x1000), Insn::Push(0), Insn::Inh(Inherent::Pop),
+            Insn::Xlo(0), Insn::Push(1), Insn::Xlo(0x1000),
+            Insn::Inh(Inherent::Alloc), Insn::Inh(Inherent::Pop),
+            Insn::Val(1),
+        ]);
+        for &auto in [false, true].iter() {
+            let mut m = Machine::builder(&code).compact_on_pop(auto).build();
+            let start = m.tos();
+            for _ in 0..3 {
+                m.step().unwrap();
+            }
+            assert_eq!(m.tos(), start);
+            if !auto {
+                assert!(m.mem.bytes.capacity() > 0x1000);
+                m.compact();
+            }
+            assert!(m.mem.bytes.capacity() < 0x1000);
+            // A named object that grew keeps its room after the pop, since
+            // it's still an item of the frame below.
+            for _ in 0..5 {
+                m.step().unwrap();
+            }
+            if !auto {
+                m.compact();
+            }
+            assert_eq!(m.mem.bytes.len(), m.tos() as usize);
+            m.check_invariants().unwrap();
+            m.step().unwrap();
+            let obj = m.x.as_object().unwrap();
+            assert_eq!(obj.cap(&m.mem), 0x1000);
+        }
+    }
+
+    #[test]
+    fn push_existing_object() {
+        let code = encode(&[
+            Insn::Xlo(16), Insn::Push(1), Insn::Xlo(5), Insn::Def(2),
+            Insn::Inh(Inherent::Pop),
+            Insn::Xlo(7), Insn::Def(3),
+            Insn::Val(1), Insn::Push(0), Insn::Val(2), Insn::Xlo(6),
+            Insn::Def(4), Insn::Inh(Inherent::Pop),
+            Insn::Val(1), Insn::Def(0),
+        ]);
+        let mut m = Machine::new(&code);
+        for _ in 0..7 {
+            m.step().unwrap();
+        }
+        let tos = m.tos();
+        let frame = m.fp;
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        assert_ne!(m.fp, frame);
+        assert!(matches!(m.x, XData::I32(5)));
+        assert_eq!(m.frames().count(), 2);
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.fp, frame);
+        assert_eq!(m.tos(), tos);
+        m.check_invariants().unwrap();
+        let obj = m.run(10).unwrap().0.as_object().unwrap();
+        assert_eq!(obj.iter_items(&m.mem).count(), 2);
+
+        // Frames, things outside the stack, and objects already on the
+        // chain can't be entered.
+        let mut m = Machine::new(&code);
+        for &bad in [m.gp, m.fp, ObjPtr::at(m.tos())].iter() {
+            m.x = XData::Object(bad);
+            m.pc = 4*8;
+            match m.step() {
+                Err(Fault { kind: InsnException::NotAnObject(_), .. }) => (),
+                r => panic!("expected NotAnObject, got {:?}", r),
+            }
+        }
+        m.pc = 0;
+        for _ in 0..9 {
+            m.step().unwrap();
+        }
+        m.x = XData::Object(m.fp);
+        m.pc = 4*8;
+        match m.step() {
+            Err(Fault { kind: InsnException::NotAnObject(_), .. }) => (),
+            r => panic!("expected NotAnObject, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn items_must_end_at_size() {
+        let code = encode(&[
+            Insn::Xlo(5), Insn::Def(1), Insn::Xlo(0), Insn::Push(1),
+            Insn::Xlo(6), Insn::Def(2), Insn::Xlo(0), Insn::Push(0),
+        ]);
+        let mut m = Machine::new(&code);
+        for _ in 0..8 {
+            m.step().unwrap();
+            // The named object outgrows the size its parent recorded.
+            m.check_invariants().unwrap();
+        }
+        m.gp.set_size(&mut m.mem, 4);
+        let err = m.check_invariants().unwrap_err();
+        assert_eq!(err, IntegrityError {
+            frame: Some(m.gp), rule: "items don't end at size",
+        });
+        assert!(err.to_string().ends_with(": items don't end at size"));
+    }
+
+    #[test]
+    fn dump_program_round_trips() {
+        let src = "
+            ldi 0x1234_5678
+            def 1
+            xlo 0
+            push 2
+            inh pop
+            val 1
+            jumpz +8
+            jump #0000_0000
+            def 0
+        ";
+        let code = assemble(src).unwrap();
+        for &separate in [false, true].iter() {
+            let m = Machine::builder(&code).separate_code(separate).build();
+            let dump = m.dump_program();
+            assert_eq!(dump, disassemble(&code));
+            assert_eq!(assemble(&dump).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn item_names() {
+        let src = "
+            xlo 5
+            def counter
+            def 1
+            val counter
+            def total
+            val missing
+        ";
+        let (code, names) = assemble_with_names(src).unwrap();
+        let unnamed = Err(AssembleError {
+            line: 3, msg: "item name needs `assemble_with_names`",
+        });
+        assert_eq!(assemble(src), unnamed);
+        assert_eq!(assemble_object(src).err(), unnamed.err());
+        // Id 1 is taken by `def 1`.
+        let id = |name| names.id(name).unwrap().get();
+        assert_eq!((id("counter"), id("total"), id("missing")), (2, 3, 4));
+        assert_eq!(code[1], Insn::Def(2).as_u32());
+        assert_eq!(code[3], Insn::Val(2).as_u32());
+
+        let mut m = Machine::builder(&code).names(names).build();
+        let fault = match m.run(10) {
+            Err(RunError::Fault(fault)) => fault,
+            r => panic!("expected a fault, got {:?}", r),
+        };
+        assert_eq!(m.describe_fault(&fault),
+            "item missing not found @ #0000_0014");
+        let mut s = String::new();
+        m.write_stack(&mut s).unwrap();
+        assert!(s.contains("  id counter: I32\n"));
+        assert!(s.contains("  id #0000_0001: I32\n"));
+        assert!(s.contains("  id total: I32\n"));
+    }
+
+    #[test]
+    fn assemble_labels_and_entry() {
+        let src = "
+            table:  xlo 7       ; data, never run
+                    xlo 9
+            .entry start
+            start:  xlo table
+                    def 1
+                    jumpz done
+                    jump start
+            done:   def 0
+        ";
+        let obj = assemble_object(src).unwrap();
+        assert_eq!(obj.entry, 8);
+        assert_eq!(obj.relocs, vec![2, 4, 5]);
+        assert_eq!(&obj.code[2..6], &encode(&[
+            Insn::Xlo(0), Insn::Def(1), Insn::Jump(JUMP_IF_ZERO | 0x18),
+            Insn::Jump(0x8),
+        ])[..]);
+        assert_eq!(assemble(src).unwrap(), obj.code);
+
+        let symbols = |s: &[(&str, u32)]| -> Vec<(String, u32)> {
+            s.iter().map(|&(l, a)| (l.to_string(), a)).collect()
+        };
+        assert_eq!(obj.symbols,
+            symbols(&[("table", 0x0), ("start", 0x8), ("done", 0x18)]));
+        assert_eq!(obj.lines, vec![2, 3, 5, 6, 7, 8, 9]);
+        let loaded = ObjectFile::from_bytes(&obj.to_bytes()).unwrap();
+        assert_eq!(loaded.symbols, obj.symbols);
+        assert_eq!(loaded.lines, obj.lines);
+
+        let mut m = Machine::load_at(&obj, 0x100).unwrap();
+        assert_eq!(m.pc, 0x108);
+        m.step().unwrap();
+        assert!(matches!(m.x, XData::I32(0x100)));
+        let mut moved = obj.clone();
+        moved.relocate(0x100).unwrap();
+        assert_eq!(moved.symbols[2], ("done".to_string(), 0x118));
+
+        let err = |line, msg| Err(AssembleError { line, msg });
+        assert_eq!(assemble("jump nowhere"), err(1, "label isn't defined"));
+        assert_eq!(assemble("a: def 1\na: def 2"),
+            err(2, "label is already defined"));
+        assert_eq!(assemble("2a: def 1"), err(1, "label isn't a name"));
+        assert!(matches!(assemble_object("a: def 0\n.entry b"),
+            Err(AssembleError { line: 2, .. })));
+        assert_eq!(assemble_object("def 0\n.entry end\nend:").err(),
+            Some(AssembleError {
+                line: 2, msg: "entry label isn't at an instruction",
+            }));
+    }
+
+    #[test]
+    fn assemble_and_link_modules() {
+        use link::{link, LinkError};
+
+        let main = assemble_module("
+            .extern triple
+            .global main
+            main:   xlo 5
+                    def 1
+                    jump triple
+        ").unwrap();
+        let lib = assemble_module("
+            .global triple
+                    xlo 0       ; padding, so the address isn't 0
+            triple: xlo 3
+                    inh mul
+                    def 0
+        ").unwrap();
+        assert_eq!(main.globals, vec![("main".to_string(), 0)]);
+        assert_eq!(main.externs, vec![(2, "triple".to_string())]);
+        assert_eq!(lib.globals, vec![("triple".to_string(), 1)]);
+
+        let (code, symbols) = link(&[main.clone(), lib]).unwrap();
+        assert_eq!(symbols["triple"], 0x10);
+        match Machine::new(&code).run(10) {
+            Ok((XData::I32(15), _)) => (),
+            r => panic!("expected 15, got {:?}", r),
+        }
+        assert_eq!(
+            link(&[main]).unwrap_err(),
+            LinkError::UndefinedSymbol("triple".to_string()));
+
+        let err = |line, msg| Err(AssembleError { line, msg });
+        assert_eq!(assemble(".extern f\njump f"),
+            err(2, "extern label needs `assemble_module`"));
+        assert_eq!(assemble_module(".global g").err(),
+            Some(AssembleError { line: 1, msg: "label isn't defined" }));
+        assert_eq!(
+            assemble_module(".extern f\nf: jump f").err(),
+            Some(AssembleError {
+                line: 2, msg: "label is both extern and defined here",
+            }));
+    }
+}
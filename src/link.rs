@@ -0,0 +1,107 @@
+//! Joining separately built code modules into one program.
+
+use std::collections::BTreeMap;
+
+use crate::{relocate_jump, Insn};
+
+/// Code addresses of global symbols, by name.
+pub type SymbolTable = BTreeMap<String, u32>;
+
+/// One module's code, plus what the linker needs to place it.
+/// `assemble_module` builds one from source.
+#[derive(Clone, Debug, Default)]
+pub struct Module {
+    pub code: Vec<Insn>,
+    /// Indices of instructions whose immediates are addresses within this
+    /// module, counted from the module's own first word.
+    pub relocs: Vec<u32>,
+    /// Symbols this module exports, as instruction indices.
+    pub globals: Vec<(String, u32)>,
+    /// Instructions whose immediates get a symbol's final address added,
+    /// which may come from any module (this one included).
+    pub externs: Vec<(u32, String)>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LinkError {
+    DuplicateSymbol(String),
+    UndefinedSymbol(String),
+    /// An index past the end of module `module`'s code.
+    BadIndex { module: usize, index: u32 },
+    /// Instruction `index` of module `module` is invalid after patching,
+    /// usually because the address doesn't fit in its immediate.
+    BadInsn { module: usize, index: u32, msg: &'static str },
+}
+
+/// Lay the modules out one after another starting at address 0, patch
+/// their relocations and symbol references, and encode the result.
+pub fn link(modules: &[Module]) -> Result<(Vec<u32>, SymbolTable), LinkError> {
+    let mut bases = Vec::with_capacity(modules.len());
+    let mut base = 0;
+    for m in modules {
+        bases.push(base);
+        base += 4*m.code.len() as u32;
+    }
+
+    let mut symbols = SymbolTable::new();
+    for (i, m) in modules.iter().enumerate() {
+        for (name, index) in &m.globals {
+            check_index(i, m, *index)?;
+            let addr = bases[i] + 4*index;
+            if symbols.insert(name.clone(), addr).is_some() {
+                return Err(LinkError::DuplicateSymbol(name.clone()));
+            }
+        }
+    }
+
+    let mut out = Vec::with_capacity(base as usize / 4);
+    for (i, m) in modules.iter().enumerate() {
+        let mut code = m.code.clone();
+        let mut patch = |index: u32, delta: u32| -> Result<(), LinkError> {
+            check_index(i, m, index)?;
+            let insn = &mut code[index as usize];
+            *insn = add_to_imm(*insn, delta).map_err(|msg| {
+                LinkError::BadInsn { module: i, index, msg }
+            })?;
+            Ok(())
+        };
+        for &index in &m.relocs {
+            patch(index, bases[i])?;
+        }
+        for (index, name) in &m.externs {
+            let addr = *symbols.get(name)
+                .ok_or_else(|| LinkError::UndefinedSymbol(name.clone()))?;
+            patch(*index, addr)?;
+        }
+        for (index, insn) in code.iter().enumerate() {
+            out.push(insn.try_as_u32().map_err(|msg| {
+                LinkError::BadInsn { module: i, index: index as u32, msg }
+            })?);
+        }
+    }
+    Ok((out, symbols))
+}
+
+fn check_index(module: usize, m: &Module, index: u32) -> Result<(), LinkError> {
+    if (index as usize) < m.code.len() {
+        Ok(())
+    } else {
+        Err(LinkError::BadIndex { module, index })
+    }
+}
+
+fn add_to_imm(insn: Insn, delta: u32) -> Result<Insn, &'static str> {
+    use Insn::*;
+    let add = |n: u32| n.checked_add(delta).ok_or("address overflows u32");
+    Ok(match insn {
+        Def(n) => Def(add(n)?),
+        Set(n) => Set(add(n)?),
+        Push(n) => Push(add(n)?),
+        Jump(n) => Jump(relocate_jump(n, delta)
+            .ok_or("jump target doesn't fit in bits 26..0")?),
+        Val(n) => Val(add(n)?),
+        Xlo(n) => Xlo(add(n)?),
+        Xhi(n) => Xhi(add(n)?),
+        Inh(_) => return Err("inherent has no address immediate"),
+    })
+}
@@ -0,0 +1,1837 @@
+//! The machine itself: its state, `MachineBuilder`, stepping, and the
+//! drivers built on `step`.
+
+use core::cell::Cell;
+use core::convert::TryInto;
+use core::fmt;
+use core::num::NonZeroU32;
+use core::ops::ControlFlow;
+use std::io::{self, Read};
+use std::time::Instant;
+
+use crate::format::{friendly_hex_u32, Paint};
+use crate::mem::{
+    BASE_OFFSET, OBJ_HEADER_SIZE, PREV_OFFSET, RET_OFFSET, SIZE_OFFSET,
+};
+#[cfg(feature = "trace-events")]
+use crate::{trace, TraceEvent};
+use crate::{
+    disassemble, item_header_to_u32, try_item_header_from_u32, verify,
+    ColorMode, Fault, FormatStyle, FrameKind, Inherent, Insn, InsnDecoder,
+    InsnException, IntegrityError, ItemId, ItemNames, ItemPtr, LoadError,
+    LobError, Mem, ObjPtr, ObjectFile, RunError, Type, WriteLog, XData,
+    JUMP_IF_ZERO,
+};
+
+type StepHook = Box<dyn FnMut(&StepInfo) -> ControlFlow<()>>;
+
+/// What `Machine::on_step` sees before each instruction.
+#[derive(Clone, Copy, Debug)]
+pub struct StepInfo {
+    pub pc: u32,
+    pub insn: Insn,
+    pub x: XData,
+    pub fp: ObjPtr,
+}
+
+/// The result of `Machine::peek_step`.
+#[derive(Clone, Debug)]
+pub struct StepEffect {
+    pub pc: u32,
+    pub x: XData,
+    pub fp: ObjPtr,
+    /// `(addr, old, new)` for each word that would change. Words past the
+    /// current end of memory count as 0 before the step.
+    pub writes: Vec<(u32, u32, u32)>,
+    /// Memory size after the step. If it's bigger than now, the step would
+    /// grow the stack.
+    pub mem_len: u32,
+    /// `Some` if the step would halt the program.
+    pub halted: Option<XData>,
+}
+
+/// Why one of the `run_*` drivers returned.
+#[derive(Clone, Copy, Debug)]
+pub enum StopReason {
+    Halted(XData),
+    StepLimit,
+    Predicate,
+    ReachedPc,
+    Defined(ItemPtr),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    FramePushed(ObjPtr),
+    FramePopped,
+    ItemDefined(ItemId, Type),
+    Jumped(u32),
+    Halted(XData),
+}
+
+/// Cloning a machine copies all of its state except the callbacks, which
+/// the copy starts without.
+#[derive(Clone)]
+pub struct Machine {
+    pub x: XData,
+    pub pc: u32,
+    pub fp: ObjPtr,
+    pub gp: ObjPtr,
+    pub mem: Mem,
+    /// The code, if it's kept apart from `mem`.
+    code: Option<Mem>,
+    entry: u32,
+    /// Everything in `mem` below this is code (plus padding).
+    code_end: u32,
+    code_write_policy: CodeWritePolicy,
+    scope_mode: ScopeMode,
+    /// `(pc, addr)` for each store below `code_end` under
+    /// `CodeWritePolicy::Log`.
+    code_writes: Vec<(u32, u32)>,
+    /// Every code word already decoded, indexed by `pc / 4`. Only kept when
+    /// the code can't change under it.
+    pub(crate) decoded: Option<Vec<Insn>>,
+    /// Set once `Pop` has popped the root frame. There's no frame left, so
+    /// every later step underflows.
+    stack_empty: bool,
+    pub(crate) depth: u32,
+    max_depth: u32,
+    max_mem: u32,
+    /// Memory can't grow past this many bytes.
+    mem_limit: u32,
+    hooks: Hooks,
+    seed: u32,
+    rng: u32,
+    allow_clock: bool,
+    deterministic: bool,
+    start: Instant,
+    pub(crate) steps: u64,
+    strict: bool,
+    /// Give memory back to the allocator after every `Pop`.
+    compact_on_pop: bool,
+    /// Shown in place of ids in dumps.
+    names: ItemNames,
+    /// Bumped whenever a frame gains or loses items.
+    items_gen: u64,
+    /// The last global `find` resolved, while `items_gen` and `fp` are
+    /// unchanged.
+    global_hit: Cell<Option<GlobalHit>>,
+}
+
+/// The callbacks set on a `Machine`. A clone has none, since boxed
+/// closures can't be copied.
+#[derive(Default)]
+struct Hooks {
+    on_push: Option<Box<dyn FnMut(ObjPtr, u32)>>,
+    on_pop: Option<Box<dyn FnMut(ObjPtr, u32)>>,
+    on_step: Option<StepHook>,
+    #[cfg(feature = "trace-events")]
+    on_trace: Option<trace::TraceHook>,
+}
+
+impl Clone for Hooks {
+    fn clone(&self) -> Self {
+        Hooks::default()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct GlobalHit {
+    items_gen: u64,
+    fp: ObjPtr,
+    id: ItemId,
+    item: ItemPtr,
+}
+
+/// What a store below `Machine::code_end` does.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CodeWritePolicy {
+    /// Code is read-only: the store fails with `WriteToCode`.
+    #[default]
+    ReadOnly,
+    /// The store fails with `SelfModifyingCode`, for hunting down stray
+    /// pointer math in programs that aren't meant to modify themselves.
+    Trap,
+    /// The store goes through and is recorded in `Machine::code_writes`.
+    Log,
+    /// The store goes through silently.
+    Allow,
+}
+
+/// Which chain of frames `Val` searches for an id.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ScopeMode {
+    /// Follow `prev`, the frames that are still running (`Machine::find`).
+    #[default]
+    Dynamic,
+    /// Follow `base`, the frames the code was defined in
+    /// (`Machine::find_lexical`).
+    Lexical,
+}
+
+/// Builtin 0 is reserved.
+pub const BUILTIN_RANDOM: u32 = 1;
+/// Nanoseconds since the machine started (or was reset), saturating at
+/// `u32::MAX`.
+pub const BUILTIN_CLOCK: u32 = 2;
+
+const DEFAULT_SEED: u32 = 0x2545_F491;
+
+pub struct MachineBuilder<'a> {
+    code: &'a [u32],
+    entry: u32,
+    seed: u32,
+    allow_clock: bool,
+    deterministic: bool,
+    strict: bool,
+    separate_code: bool,
+    code_write_policy: CodeWritePolicy,
+    predecode: bool,
+    scope_mode: ScopeMode,
+    log_writes: Option<usize>,
+    mem_limit: u32,
+    compact_on_pop: bool,
+    names: ItemNames,
+}
+
+impl<'a> MachineBuilder<'a> {
+    pub fn entry(mut self, entry: u32) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    /// Seed for `BUILTIN_RANDOM`. xorshift gets stuck at 0, so a zero seed
+    /// is replaced with the default.
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.seed = if seed == 0 { DEFAULT_SEED } else { seed };
+        self
+    }
+
+    /// Whether `BUILTIN_CLOCK` is available. Sandboxes that don't want to
+    /// leak timing can turn it off.
+    pub fn allow_clock(mut self, allow: bool) -> Self {
+        self.allow_clock = allow;
+        self
+    }
+
+    /// In deterministic mode `BUILTIN_CLOCK` counts executed instructions
+    /// (one nanosecond each) instead of reading the host clock, so replays
+    /// see the same times.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// In strict mode no program can make the interpreter panic: broken
+    /// internal invariants come back as `InsnException::CorruptState`.
+    /// Otherwise they panic, which is handier while working on the VM.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Put the code in its own buffer, so `pc` and `Code` values address
+    /// that buffer and everything else addresses `mem`, which then holds
+    /// only data. `mem` starts with one word of padding so the root frame
+    /// isn't at 0. By default code and data share `mem`, code first.
+    pub fn separate_code(mut self, separate_code: bool) -> Self {
+        self.separate_code = separate_code;
+        self
+    }
+
+    pub fn code_writes(mut self, policy: CodeWritePolicy) -> Self {
+        self.code_write_policy = policy;
+        self
+    }
+
+    /// Run `Machine::compact` after every `Pop`, trading some speed for
+    /// memory in programs that go deep and come back.
+    pub fn compact_on_pop(mut self, compact: bool) -> Self {
+        self.compact_on_pop = compact;
+        self
+    }
+
+    /// Show these names instead of ids in stack and object dumps, and in
+    /// `Machine::describe_fault`.
+    pub fn names(mut self, names: ItemNames) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Decode the whole program once up front instead of on every step.
+    /// This only takes effect if the code is read-only: either it's in its
+    /// own buffer, or stores to it fail (`CodeWritePolicy::ReadOnly` or
+    /// `Trap`).
+    pub fn predecode(mut self, predecode: bool) -> Self {
+        self.predecode = predecode;
+        self
+    }
+
+    /// `Push` sets a new frame's `base` and `prev` to the same frame, so the
+    /// two modes only disagree once something points `base` elsewhere.
+    pub fn scope_mode(mut self, mode: ScopeMode) -> Self {
+        self.scope_mode = mode;
+        self
+    }
+
+    /// Record every store to `mem`, keeping the first `max_entries`; see
+    /// `Machine::write_log`. Without this, stores don't pay for the log.
+    pub fn log_writes(mut self, max_entries: usize) -> Self {
+        self.log_writes = Some(max_entries);
+        self
+    }
+
+    /// Fault with `OutOfMemory` instead of growing memory past
+    /// `max_bytes`. The code and the root frame are always there, even if
+    /// they take more than that. There's no limit by default.
+    pub fn mem_limit(mut self, max_bytes: u32) -> Self {
+        self.mem_limit = max_bytes;
+        self
+    }
+
+    pub fn build(self) -> Machine {
+        let code = self.code;
+        let mut mem = Vec::with_capacity(4*code.len() + 0x100);
+        for chunk in code.iter().map(|&i| i.to_le_bytes()) {
+            mem.extend_from_slice(&chunk);
+        }
+        self.build_with(mem)
+    }
+
+    /// Like `build`, but reads `len` bytes of code straight into memory
+    /// instead of taking it from `code`, so the program is never held twice.
+    pub fn build_from_reader(self, r: &mut impl Read, len: u64)
+        -> Result<Machine, LoadError>
+    {
+        const CHUNK: usize = 0x1_0000;
+        if len & 0x3 != 0 {
+            return Err(LoadError::UnalignedCode);
+        }
+        // Leave room for the root frame header above the code.
+        if len > (u32::MAX - OBJ_HEADER_SIZE) as u64 {
+            return Err(LoadError::TooLarge(len));
+        }
+        let len = len as usize;
+        let mut mem = Vec::with_capacity(len + 0x100);
+        let mut chunk = vec![0; CHUNK.min(len)];
+        while mem.len() < len {
+            let want = chunk.len().min(len - mem.len());
+            let n = match r.read(&mut chunk[..want]) {
+                Ok(0) => return Err(LoadError::Truncated),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(LoadError::Io(e.kind())),
+            };
+            mem.extend_from_slice(&chunk[..n]);
+        }
+        Ok(self.build_with(mem))
+    }
+
+    /// Build a machine running `obj`, with its code starting at `base`
+    /// instead of 0 and relocated to match. The bytes below `base` are
+    /// zeroed and count as part of the read-only code region. The entry
+    /// point comes from `obj`, not from `entry`.
+    pub fn build_from_object(self, obj: &ObjectFile, base: u32)
+        -> Result<Machine, LoadError>
+    {
+        let mut obj = obj.clone();
+        obj.relocate(base)?;
+        let mut mem = vec![0; base as usize];
+        mem.reserve(4*obj.code.len() + 0x100);
+        for w in &obj.code {
+            mem.extend_from_slice(&w.to_le_bytes());
+        }
+        Ok(self.entry(obj.entry).build_with(mem))
+    }
+
+    pub(crate) fn build_with(self, mut mem: Vec<u8>) -> Machine {
+        // With no code at all, start `pc` past the end so the first step
+        // faults instead of decoding the padding below.
+        let no_code = mem.is_empty();
+        let code = if self.separate_code {
+            let code = std::mem::replace(&mut mem, Vec::with_capacity(0x100));
+            Some(Mem::from_vec(code))
+        } else {
+            None
+        };
+        if mem.is_empty() {
+            // Keep the root frame off address 0, which means "none".
+            mem.resize(4, 0);
+        }
+        let code_end = mem.len() as u32;
+        let read_only = code.is_some() || match self.code_write_policy {
+            CodeWritePolicy::ReadOnly | CodeWritePolicy::Trap => true,
+            CodeWritePolicy::Log | CodeWritePolicy::Allow => false,
+        };
+        let decoded = if self.predecode && read_only {
+            let code = code.as_ref().map_or(&mem[..], |c| c.as_bytes());
+            Some(InsnDecoder::from_bytes(code).map(Result::unwrap).collect())
+        } else {
+            None
+        };
+        let entry = if no_code { code_end } else { self.entry };
+        let fp = ObjPtr::at(code_end);
+        mem.resize(mem.len() + OBJ_HEADER_SIZE as usize, 0);
+        let max_mem = mem.len() as u32;
+        let mut mem = Mem::from_vec(mem);
+        mem.log = self.log_writes.map(|max| Box::new(WriteLog::new(max)));
+        Machine {
+            x: XData::I32(0),
+            pc: entry,
+            fp,
+            gp: fp,
+            mem,
+            code,
+            entry,
+            code_end,
+            code_write_policy: self.code_write_policy,
+            scope_mode: self.scope_mode,
+            code_writes: Vec::new(),
+            decoded,
+            stack_empty: false,
+            depth: 0,
+            max_depth: 0,
+            max_mem,
+            mem_limit: self.mem_limit,
+            hooks: Hooks::default(),
+            seed: self.seed,
+            rng: self.seed,
+            allow_clock: self.allow_clock,
+            deterministic: self.deterministic,
+            start: Instant::now(),
+            steps: 0,
+            strict: self.strict,
+            compact_on_pop: self.compact_on_pop,
+            names: self.names,
+            items_gen: 0,
+            global_hit: Cell::new(None),
+        }
+    }
+}
+
+/// The frames on the `prev` chain, from the current one down to the root.
+/// An object entered with `Push` can sit below the frame it returns to, so
+/// order says nothing; instead the walk stops, rather than looping, after
+/// as many frames as could fit in memory.
+pub struct Frames<'a> {
+    mem: &'a Mem,
+    next: Option<ObjPtr>,
+    left: u32,
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = ObjPtr;
+
+    fn next(&mut self) -> Option<ObjPtr> {
+        let fp = self.next?;
+        self.left = self.left.checked_sub(1)?;
+        self.next = fp.try_prev(self.mem).ok().flatten();
+        Some(fp)
+    }
+}
+
+/// A machine with no program. Its first step faults with `PcOutOfCode`.
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new(&[])
+    }
+}
+
+impl Machine {
+    pub fn new(code: &[u32]) -> Self {
+        Self::builder(code).build()
+    }
+
+    /// Like `new`, but fails instead of building a machine whose code
+    /// leaves no room for the root frame.
+    pub fn try_new(code: &[u32]) -> Result<Self, LoadError> {
+        let len = 4*code.len() as u64;
+        if len > (u32::MAX - OBJ_HEADER_SIZE) as u64 {
+            return Err(LoadError::TooLarge(len));
+        }
+        Ok(Self::new(code))
+    }
+
+    /// Build, `verify`, and run `code` for at most `limit` steps, and
+    /// return what it halted with.
+    pub fn validate_and_run(code: &[u32], limit: u64)
+        -> Result<XData, LobError>
+    {
+        let mut m = Self::try_new(code).map_err(LobError::Load)?;
+        verify(code).map_err(LobError::Verify)?;
+        match m.run_while(|_| true, limit).map_err(LobError::Fault)? {
+            StopReason::Halted(x) => Ok(x),
+            _ => Err(LobError::StepLimit(limit)),
+        }
+    }
+
+    /// A machine whose memory can't grow past `max_bytes`; see
+    /// `MachineBuilder::mem_limit`.
+    pub fn with_limit(code: &[u32], max_bytes: u32) -> Self {
+        Self::builder(code).mem_limit(max_bytes).build()
+    }
+
+    /// Execution starts at byte address `entry` instead of 0, so data can be
+    /// placed in front of the first instruction.
+    pub fn with_entry(code: &[u32], entry: u32) -> Self {
+        Self::builder(code).entry(entry).build()
+    }
+
+    pub fn builder(code: &[u32]) -> MachineBuilder<'_> {
+        MachineBuilder {
+            code,
+            entry: 0,
+            seed: DEFAULT_SEED,
+            allow_clock: true,
+            deterministic: false,
+            strict: false,
+            separate_code: false,
+            code_write_policy: CodeWritePolicy::default(),
+            predecode: false,
+            scope_mode: ScopeMode::default(),
+            log_writes: None,
+            mem_limit: u32::MAX,
+            compact_on_pop: false,
+            names: ItemNames::new(),
+        }
+    }
+
+    /// Call `f` with the new frame and the new depth each time a frame or
+    /// object is pushed. It runs after `fp` has moved.
+    pub fn on_frame_push(&mut self, f: impl FnMut(ObjPtr, u32) + 'static) {
+        self.hooks.on_push = Some(Box::new(f));
+    }
+
+    /// Call `f` with the popped frame and the new depth each time a frame is
+    /// popped. It runs after `fp` has moved back and the stack has shrunk,
+    /// so the popped frame's memory may already be gone.
+    pub fn on_frame_pop(&mut self, f: impl FnMut(ObjPtr, u32) + 'static) {
+        self.hooks.on_pop = Some(Box::new(f));
+    }
+
+    /// Call `f` before each instruction runs, with the instruction and the
+    /// state it runs in. If `f` breaks, the instruction doesn't run and
+    /// `step` fails with `Interrupted`; stepping again calls `f` again.
+    pub fn on_step(
+        &mut self, f: impl FnMut(&StepInfo) -> ControlFlow<()> + 'static,
+    ) {
+        self.hooks.on_step = Some(Box::new(f));
+    }
+
+    /// Call `f` with each event a step causes: the step itself, then any
+    /// frame push or pop, item definition, halt, or exception.
+    #[cfg(feature = "trace-events")]
+    pub fn on_trace(&mut self, f: impl FnMut(&TraceEvent) + 'static) {
+        self.hooks.on_trace = Some(Box::new(f));
+    }
+
+    /// Give memory past the top of the stack back to the allocator.
+    /// Popping only lowers the logical size, so otherwise the backing
+    /// buffer stays as big as the deepest the stack has been. Nothing
+    /// moves, so every pointer stays valid.
+    pub fn compact(&mut self) {
+        self.mem.shrink_to_fit();
+    }
+
+    /// Throw away all frames and start over from the entry point, keeping
+    /// the code.
+    pub fn reset(&mut self) {
+        let root = self.gp.addr();
+        self.mem.set_len(root as usize);
+        self.mem.set_len((root + OBJ_HEADER_SIZE) as usize);
+        self.x = XData::I32(0);
+        self.pc = self.entry;
+        self.fp = self.gp;
+        self.depth = 0;
+        self.max_depth = 0;
+        self.max_mem = self.tos();
+        self.rng = self.seed;
+        self.start = Instant::now();
+        self.steps = 0;
+        self.code_writes.clear();
+        self.stack_empty = false;
+        self.global_hit.set(None);
+        if let Some(log) = &mut self.mem.log {
+            log.clear();
+        }
+    }
+
+    /// Run builtin number `index` (what `Call` does with a `BuiltinCode`).
+    pub fn call_builtin(&mut self, index: u32) -> Result<(), InsnException> {
+        match index {
+            BUILTIN_RANDOM => {
+                self.x = XData::I32(self.next_random());
+                Ok(())
+            },
+            BUILTIN_CLOCK => {
+                if !self.allow_clock {
+                    return Err(InsnException::CapabilityDenied(index));
+                }
+                let ns = if self.deterministic {
+                    self.steps as u128
+                } else {
+                    self.start.elapsed().as_nanos()
+                };
+                self.x = XData::I32(ns.min(u32::MAX as u128) as u32);
+                Ok(())
+            },
+            _ => Err(InsnException::UnknownBuiltin(index)),
+        }
+    }
+
+    /// xorshift32. Never seeded from the OS, so a run can be replayed.
+    fn next_random(&mut self) -> u32 {
+        let mut r = self.rng;
+        r ^= r << 13;
+        r ^= r >> 17;
+        r ^= r << 5;
+        self.rng = r;
+        r
+    }
+
+    pub fn x(&self) -> XData {
+        self.x
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    /// Address of the current frame's header.
+    pub fn frame_pointer(&self) -> u32 {
+        self.fp.addr()
+    }
+
+    /// Address of the root frame's header.
+    pub fn global_pointer(&self) -> u32 {
+        self.gp.addr()
+    }
+
+    /// Number of frames above the root.
+    pub fn frame_depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Highest `frame_depth` seen since construction or `reset`.
+    pub fn max_frame_depth(&self) -> u32 {
+        self.max_depth
+    }
+
+    pub fn scope_mode(&self) -> ScopeMode {
+        self.scope_mode
+    }
+
+    /// Whether the program ended by popping the root frame.
+    pub fn stack_empty(&self) -> bool {
+        self.stack_empty
+    }
+
+    /// Largest memory size in bytes seen since construction or `reset`.
+    pub fn max_mem(&self) -> u32 {
+        self.max_mem
+    }
+
+    /// End of the read-only region at the start of `mem`: the code, or
+    /// just padding if the code is in its own buffer.
+    pub fn code_end(&self) -> u32 {
+        self.code_end
+    }
+
+    /// Every store to `mem` since construction or `reset`, if the machine
+    /// was built with `MachineBuilder::log_writes`.
+    pub fn write_log(&self) -> Option<&WriteLog> {
+        self.mem.log.as_deref()
+    }
+
+    /// `(pc, addr)` for every store below `code_end` since construction or
+    /// `reset`, if the machine was built with `CodeWritePolicy::Log`.
+    pub fn code_writes(&self) -> &[(u32, u32)] {
+        &self.code_writes
+    }
+
+    /// Load `len` bytes of little-endian code words from `r`, starting at
+    /// address 0 like `new`.
+    pub fn from_reader(r: &mut impl Read, len: u64)
+        -> Result<Self, LoadError>
+    {
+        Self::builder(&[]).build_from_reader(r, len)
+    }
+
+    pub fn load(obj: &ObjectFile) -> Result<Self, LoadError> {
+        Self::load_at(obj, 0)
+    }
+
+    /// Same as `MachineBuilder::build_from_object` with default settings.
+    pub fn load_at(obj: &ObjectFile, base: u32) -> Result<Self, LoadError> {
+        Self::builder(&[]).build_from_object(obj, base)
+    }
+
+    pub fn print_stack(&self) {
+        self.print_stack_color(ColorMode::Never);
+    }
+
+    /// Like `print_stack`, but with ANSI colors if `color` says so.
+    pub fn print_stack_color(&self, color: ColorMode) {
+        let mut s = String::new();
+        let paint = Paint(color.enabled());
+        self.write_stack_impl(&mut s, FormatStyle::default(), paint).unwrap();
+        print!("{}", s);
+    }
+
+    pub fn write_stack(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        self.write_stack_with(w, FormatStyle::default())
+    }
+
+    pub fn write_stack_with(&self, w: &mut impl fmt::Write, style: FormatStyle)
+        -> fmt::Result
+    {
+        self.write_stack_impl(w, style, Paint(false))
+    }
+
+    /// Like `write_stack_with`, but with ANSI colors: addresses in cyan,
+    /// types in yellow, and the current frame in bold green.
+    pub fn write_stack_colored(
+        &self,
+        w: &mut impl fmt::Write,
+        style: FormatStyle,
+    ) -> fmt::Result {
+        self.write_stack_impl(w, style, Paint(true))
+    }
+
+    fn write_stack_impl(
+        &self,
+        w: &mut impl fmt::Write,
+        style: FormatStyle,
+        paint: Paint,
+    ) -> fmt::Result {
+        for obj in self.frames() {
+            let fp = obj.addr();
+            let prev = self.load_u32(fp + PREV_OFFSET);
+            let head = format!("{}:", style.u32(fp));
+            if fp == self.fp.addr() {
+                writeln!(w, "{}", paint.current(head))?;
+            } else {
+                writeln!(w, "{}", paint.addr(head))?;
+            }
+            let field = |off| style.u32(self.load_u32(fp + off));
+            let addr = |off| paint.addr(field(off));
+            writeln!(w, "  cap  = {}", style.u32(obj.cap(&self.mem)))?;
+            writeln!(w, "  size = {}", field(SIZE_OFFSET))?;
+            writeln!(w, "  base = {}", addr(BASE_OFFSET))?;
+            writeln!(w, "  prev = {}", paint.addr(style.u32(prev)))?;
+            writeln!(w, "  ret  = {}", addr(RET_OFFSET))?;
+            writeln!(w, "  kind = {:?}", obj.kind(&self.mem))?;
+            obj.write_impl(&self.mem, w, style, paint, &self.names)?;
+        }
+        Ok(())
+    }
+
+    pub fn print_obj(&self, obj: ObjPtr) {
+        let mut s = String::new();
+        self.write_obj(&mut s, obj).unwrap();
+        print!("{}", s);
+    }
+
+    /// Same as `ObjPtr::write`, but with this machine's item names.
+    pub fn write_obj(&self, w: &mut impl fmt::Write, obj: ObjPtr)
+        -> fmt::Result
+    {
+        self.write_obj_with(w, obj, FormatStyle::default())
+    }
+
+    /// Same as `ObjPtr::write_with`, but with this machine's item names.
+    pub fn write_obj_with(
+        &self,
+        w: &mut impl fmt::Write,
+        obj: ObjPtr,
+        style: FormatStyle,
+    ) -> fmt::Result {
+        obj.write_impl(&self.mem, w, style, Paint(false), &self.names)
+    }
+
+    /// The names from `MachineBuilder::names`.
+    pub fn names(&self) -> &ItemNames {
+        &self.names
+    }
+
+    /// Like `fault`'s `Display`, but naming the item an item exception is
+    /// about: the one the faulting instruction looked for, or the one that
+    /// was already there.
+    pub fn describe_fault(&self, fault: &Fault) -> String {
+        let name = |id| self.names.describe(id, FormatStyle::default());
+        let pc = friendly_hex_u32(fault.pc);
+        match fault.kind {
+            InsnException::ItemNotFound => match self.fetch(fault.pc) {
+                Ok(Insn::Val(id)) | Ok(Insn::Set(id)) => {
+                    format!("item {} not found @ #{}", name(ItemId(id)), pc)
+                },
+                _ => fault.to_string(),
+            },
+            InsnException::ItemExistsInFrame(p) => match self.item_header(p.0)
+            {
+                Ok((_, id)) => format!(
+                    "item {} already exists in frame @ #{}", name(id), pc),
+                Err(_) => fault.to_string(),
+            },
+            _ => fault.to_string(),
+        }
+    }
+
+    pub fn frames(&self) -> Frames<'_> {
+        let left = self.mem.len() / OBJ_HEADER_SIZE;
+        Frames { mem: &self.mem, next: Some(self.fp), left }
+    }
+
+    /// The innermost frame whose header or reserved body holds `addr`, or
+    /// `None` if it's in the code or outside every frame.
+    pub fn frame_containing(&self, addr: u32) -> Option<ObjPtr> {
+        if addr < self.code_end {
+            return None;
+        }
+        self.frames().find(|fp| {
+            let body = fp.addr() as u64 + OBJ_HEADER_SIZE as u64;
+            let end = fp.try_cap(&self.mem).map(|cap| body + cap as u64);
+            fp.addr() <= addr && end.is_ok_and(|end| (addr as u64) < end)
+        })
+    }
+
+    /// The item whose header or value holds `addr`, along with the frame
+    /// or object it's directly in. Objects that are items are searched
+    /// too, so this finds the innermost item: an address in an object's
+    /// header or spare room gives the object itself.
+    pub fn item_containing(&self, addr: u32)
+        -> Option<(ObjPtr, ItemPtr, ItemId, Type)>
+    {
+        let mem = &self.mem;
+        let addr = addr as u64;
+        let mut obj = self.frame_containing(addr as u32)?;
+        let mut found = None;
+        'search: loop {
+            let mut p = obj.try_body_offset(0).ok()? as u64;
+            let end = p + obj.try_size(mem).ok()? as u64;
+            while p < end && p <= addr {
+                let header = mem.try_load_u32(p as u32).ok()?;
+                let (ty, id) = try_item_header_from_u32(header)?;
+                let len = match ty {
+                    Type::BuiltinCode | Type::Code | Type::I32 => 4,
+                    Type::Object => {
+                        let cap = ObjPtr::at(p as u32 + 4).try_cap(mem).ok()?;
+                        OBJ_HEADER_SIZE as u64 + cap as u64
+                    },
+                };
+                if addr < p + 4 + len {
+                    found = Some((obj, ItemPtr(p as u32), id, ty));
+                    if ty == Type::Object {
+                        obj = ObjPtr::at(p as u32 + 4);
+                        continue 'search;
+                    }
+                    break;
+                }
+                p += 4 + len;
+            }
+            return found;
+        }
+    }
+
+    /// Every item in every frame on the `prev` chain, objects' items
+    /// included, each with the frame or object it's directly in. Frames
+    /// come innermost first. A named object that's currently a frame is
+    /// only listed once.
+    pub fn all_item_ids(&self) -> Vec<(ObjPtr, ItemId, Type)> {
+        let mut seen = Vec::new();
+        let mut out = Vec::new();
+        for fp in self.frames() {
+            self.collect_items(fp, &mut seen, &mut out);
+        }
+        out
+    }
+
+    fn collect_items(
+        &self,
+        obj: ObjPtr,
+        seen: &mut Vec<ObjPtr>,
+        out: &mut Vec<(ObjPtr, ItemId, Type)>,
+    ) {
+        if seen.contains(&obj) {
+            return;
+        }
+        seen.push(obj);
+        for (p, ty, id) in obj.iter_items(&self.mem).map_while(Result::ok) {
+            out.push((obj, id, ty));
+            if ty == Type::Object {
+                self.collect_items(ObjPtr::at(p.0 + 4), seen, out);
+            }
+        }
+    }
+
+    pub fn find_in_frame(&self, fp: ObjPtr, id: ItemId)
+        -> Result<Option<ItemPtr>, InsnException>
+    {
+        for item in fp.iter_items(&self.mem) {
+            let (p, _, id2) = match item {
+                Ok(item) => item,
+                Err(InsnException::CorruptFrame(_, why)) => {
+                    self.invariant(false, why)?;
+                    unreachable!()
+                },
+                Err(e) => return Err(e),
+            };
+            if id2 == id {
+                return Ok(Some(p));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn item_type(&self, item: ItemPtr) -> Result<Type, InsnException> {
+        Ok(self.item_header(item.0)?.0)
+    }
+
+    /// What `Val` would set X to for this item. A word item gives its
+    /// value; an object item gives a pointer to the object, which starts
+    /// right after the item header.
+    pub fn item_value(&self, item: ItemPtr) -> XData {
+        self.try_item_value(item).unwrap()
+    }
+
+    pub fn try_item_value(&self, item: ItemPtr)
+        -> Result<XData, InsnException>
+    {
+        let p = item.0;
+        let word = || self.mem.try_load_u32(p + 4);
+        Ok(match self.item_type(item)? {
+            Type::BuiltinCode => XData::BuiltinCode(word()?),
+            Type::Code => XData::Code(word()?),
+            Type::I32 => XData::I32(word()?),
+            Type::Object => XData::Object(ObjPtr::at(p + 4)),
+        })
+    }
+
+    /// Bytes of items in the current frame.
+    pub fn frame_size(&self) -> u32 {
+        self.fp.size(&self.mem)
+    }
+
+    /// How many items the current frame holds, not counting those inside
+    /// objects.
+    pub fn item_count_in_frame(&self) -> usize {
+        self.fp.iter_items(&self.mem).map_while(Result::ok).count()
+    }
+
+    /// What `Val(id)` would set X to, or `None` if there's no such item or
+    /// the search fails. Like `Val`, it searches by the scope mode, and id
+    /// 0 gives the global frame.
+    pub fn value_of(&self, id: ItemId) -> Option<XData> {
+        if id == ItemId(0) {
+            return Some(XData::Object(self.gp));
+        }
+        let item = self.lookup(id).ok()??;
+        self.try_item_value(item).ok()
+    }
+
+    /// Search the frames from `fp` down to (but not including) the global
+    /// frame, then the global frame, so locals shadow globals.
+    ///
+    /// A global found from a deep frame is remembered until some frame's
+    /// items change, so reading it again in a loop doesn't walk the whole
+    /// chain. Editing items through `mem` directly doesn't count as a
+    /// change.
+    pub fn find(&self, id: ItemId) -> Result<Option<ItemPtr>, InsnException> {
+        if let Some(hit) = self.global_hit.get() {
+            if hit.items_gen == self.items_gen && hit.fp == self.fp
+                && hit.id == id
+            {
+                return Ok(Some(hit.item));
+            }
+        }
+        // Floyd's algorithm, as in `find_lexical`: `slow` follows at half
+        // speed, and if `fp` ever catches up with it, the chain loops.
+        let mut fp = self.fp;
+        let mut slow = self.fp;
+        let mut n = 0;
+        while fp != self.gp {
+            if let Some(item) = self.find_in_frame(fp, id)? {
+                return Ok(Some(item));
+            }
+            fp = match fp.try_prev(&self.mem)? {
+                Some(p) => p,
+                None => break,
+            };
+            n += 1;
+            if n % 2 == 0 {
+                slow = slow.try_prev(&self.mem)?.unwrap();
+            }
+            if fp == slow {
+                return Err(
+                    InsnException::CorruptFrame(fp, "cycle in `prev` chain"));
+            }
+        }
+        let item = self.find_in_frame(self.gp, id)?;
+        if let Some(item) = item {
+            let items_gen = self.items_gen;
+            let fp = self.fp;
+            self.global_hit.set(Some(GlobalHit { items_gen, fp, id, item }));
+        }
+        Ok(item)
+    }
+
+    /// Like `find`, but walks the `base` chain (the lexical environment)
+    /// instead of `prev`.
+    pub fn find_lexical(&self, id: ItemId)
+        -> Result<Option<ItemPtr>, InsnException>
+    {
+        // Floyd's algorithm again: `slow` follows at half speed, and if `fp`
+        // ever catches up with it, the chain loops.
+        let mut fp = self.fp;
+        let mut slow = self.fp;
+        let mut n = 0;
+        loop {
+            if let Some(item) = self.find_in_frame(fp, id)? {
+                return Ok(Some(item));
+            }
+            fp = match fp.try_base(&self.mem)? {
+                Some(p) => p,
+                None => return Ok(None),
+            };
+            n += 1;
+            if n % 2 == 0 {
+                slow = slow.try_base(&self.mem)?.unwrap();
+            }
+            if fp == slow {
+                return Err(
+                    InsnException::CorruptFrame(fp, "cycle in `base` chain"));
+            }
+        }
+    }
+
+    /// Walk every frame on the `prev` chain and check everything that should
+    /// hold between instructions: each frame's body is within the stack and
+    /// its items end exactly at its size, and neither the `prev` chain nor
+    /// any `base` chain loops. Meant to be called after each `step` in
+    /// tests, so corruption shows up where it happens.
+    pub fn check_invariants(&self) -> Result<(), IntegrityError> {
+        let mem = &self.mem;
+        let machine = |rule| Err(IntegrityError { frame: None, rule });
+
+        if self.pc & 0x3 != 0 {
+            return machine("pc is unaligned");
+        }
+        if self.fp.try_body_offset(0).map_or(true, |b| b > self.tos()) {
+            return machine("fp is past the top of the stack");
+        }
+        if self.gp.try_body_offset(0).map_or(true, |b| b > self.tos()) {
+            return machine("gp is past the top of the stack");
+        }
+
+        let at = |f| move |e| IntegrityError::at(f, e);
+        if has_cycle(self.fp, |p| p.try_prev(mem)).map_err(at(self.fp))? {
+            return machine("cycle in `prev` chain");
+        }
+        let frames: Vec<_> = self.frames().collect();
+        for &f in &frames {
+            let rule = |rule| Err(IntegrityError { frame: Some(f), rule });
+            let cap = f.try_cap(mem).map_err(at(f))?;
+            if f.try_body_offset(cap).map_err(at(f))? > self.tos() {
+                return rule("cap extends past the top of the stack");
+            }
+            f.validate(mem).map_err(at(f))?;
+            // A named object that's still a frame can grow past its
+            // parent's size, but only if it's the parent's last item.
+            let mut items = f.iter_items(mem);
+            let mut last = None;
+            for item in &mut items {
+                let (item, ty, _) = item.map_err(at(f))?;
+                last = Some(ObjPtr::at(item.0 + 4))
+                    .filter(|_| ty == Type::Object);
+            }
+            let growing = last.is_some_and(|obj| frames.contains(&obj));
+            if items.next != items.end && !(growing && items.next > items.end)
+            {
+                return rule("items don't end at size");
+            }
+            if has_cycle(f, |p| p.try_base(mem)).map_err(at(f))? {
+                return rule("cycle in `base` chain");
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn load_u32(&self, addr: u32) -> u32 {
+        self.mem.load_u32(addr)
+    }
+
+    /// Where `pc` points: the code buffer if there is one, otherwise `mem`.
+    pub fn code_mem(&self) -> &Mem {
+        self.code.as_ref().unwrap_or(&self.mem)
+    }
+
+    /// The code as `disassemble` prints it, from address 0 to the end of
+    /// the code, including any code appended since. It's read from memory,
+    /// so stores into the code show up.
+    pub fn dump_program(&self) -> String {
+        let mem = self.code_mem();
+        let words: Vec<_> = (0..self.code_len() / 4)
+            .map(|i| mem.load_u32(4*i))
+            .collect();
+        disassemble(&words)
+    }
+
+    /// Add `code` after the existing code and return the address of its
+    /// first word, for building a `Code` value to `Call`. The new words
+    /// aren't relocated, so their addresses should already count from that
+    /// base (see `ObjectFile::relocate`).
+    ///
+    /// Panics unless the machine was built with `separate_code`, since
+    /// otherwise the stack sits right after the code and can't move.
+    pub fn append_code(&mut self, code: &[u32]) -> u32 {
+        let buf = self.code.as_mut().expect("append_code needs separate_code");
+        let base = buf.len();
+        for w in code {
+            buf.extend_from_slice(&w.to_le_bytes());
+        }
+        if let Some(d) = &mut self.decoded {
+            d.extend(InsnDecoder::new(code).map(Result::unwrap));
+        }
+        base
+    }
+
+    /// Bytes of code, counting any padding before it.
+    fn code_len(&self) -> u32 {
+        match &self.code {
+            Some(code) => code.len(),
+            None => self.code_end,
+        }
+    }
+
+    fn fetch(&self, pc: u32) -> Result<Insn, InsnException> {
+        if pc >= self.code_len() {
+            return Err(InsnException::PcOutOfCode(pc));
+        }
+        if let Some(d) = &self.decoded {
+            if pc & 0x3 == 0 {
+                if let Some(&insn) = d.get(pc as usize / 4) {
+                    return Ok(insn);
+                }
+            }
+        }
+        self.code_mem().try_load_u32(pc).map(Insn::from_u32)
+    }
+
+    fn store_u32(&mut self, addr: u32, val: u32) -> Result<(), InsnException> {
+        if addr < self.code_end {
+            match self.code_write_policy {
+                CodeWritePolicy::ReadOnly => {
+                    return Err(InsnException::WriteToCode(addr));
+                },
+                CodeWritePolicy::Trap => {
+                    return Err(InsnException::SelfModifyingCode(addr));
+                },
+                CodeWritePolicy::Log => self.code_writes.push((self.pc, addr)),
+                CodeWritePolicy::Allow => (),
+            }
+        }
+        self.mem.try_store_u32(addr, val)
+    }
+
+    pub(crate) fn tos(&self) -> u32 {
+        self.mem.len()
+    }
+
+    fn cap(&self) -> Result<u32, InsnException> {
+        self.fp.try_cap(&self.mem)
+    }
+
+    pub(crate) fn size(&self) -> Result<u32, InsnException> {
+        self.fp.try_size(&self.mem)
+    }
+
+    fn prev(&self) -> Result<Option<ObjPtr>, InsnException> {
+        self.fp.try_prev(&self.mem)
+    }
+
+    fn ret(&self) -> Result<Option<NonZeroU32>, InsnException> {
+        self.fp.try_ret(&self.mem)
+    }
+
+    /// Something that should never be false. See `MachineBuilder::strict`.
+    fn invariant(&self, ok: bool, what: &'static str)
+        -> Result<(), InsnException>
+    {
+        if ok {
+            Ok(())
+        } else if self.strict {
+            Err(InsnException::CorruptState(what))
+        } else {
+            panic!("invariant violated: {}", what);
+        }
+    }
+
+    fn item_header(&self, item: u32) -> Result<(Type, ItemId), InsnException> {
+        let h = self.mem.try_load_u32(item)?;
+        match try_item_header_from_u32(h) {
+            Some(header) => Ok(header),
+            None => {
+                self.invariant(false, "bad item type")?;
+                unreachable!()
+            },
+        }
+    }
+
+    /// Make `obj`, an object item somewhere on the stack, the current
+    /// frame, returning to this one when it's popped. It can't be one
+    /// that's already on the `prev` chain.
+    fn enter_object(&mut self, obj: ObjPtr) -> Result<(), InsnException> {
+        let bad = InsnException::NotAnObject(obj.addr());
+        let lowest = self.gp.try_body_offset(4)?;
+        let end = obj.try_cap(&self.mem)
+            .and_then(|cap| obj.try_body_offset(cap));
+        if obj.addr() < lowest || end.map_or(true, |end| end > self.tos()) {
+            return Err(bad);
+        }
+        let header = self.mem.try_load_u32(obj.addr() - 4)?;
+        let is_item = matches!(try_item_header_from_u32(header),
+            Some((Type::Object, _)));
+        if !is_item || obj.try_kind(&self.mem)? != FrameKind::Object
+            || self.frames().any(|f| f == obj)
+        {
+            return Err(bad);
+        }
+        obj.set_prev(&mut self.mem, Some(self.fp));
+        obj.set_ret(&mut self.mem, None);
+        // The cached global was found from whatever chain `obj` was on
+        // the last time it was entered.
+        self.global_hit.set(None);
+        self.enter_frame(obj);
+        Ok(())
+    }
+
+    /// Push a call frame with `cap` bytes of room above the current frame,
+    /// which has to be the top one.
+    fn push_frame(&mut self, cap: u32, ret: Option<NonZeroU32>)
+        -> Result<(), InsnException>
+    {
+        let new_fp = ObjPtr::at(self.fp.try_body_offset(self.cap()?)?);
+        self.set_tos(new_fp.try_body_offset(cap)?)?;
+
+        new_fp.set_cap(&mut self.mem, cap);
+        new_fp.set_kind(&mut self.mem, FrameKind::Call);
+        new_fp.set_size(&mut self.mem, 0);
+        new_fp.set_base(&mut self.mem, Some(self.fp));
+        new_fp.set_prev(&mut self.mem, Some(self.fp));
+        new_fp.set_ret(&mut self.mem, ret);
+
+        self.enter_frame(new_fp);
+        Ok(())
+    }
+
+    /// Remove the last item in the current frame, which has to be an
+    /// `I32`, and return its value.
+    fn pop_operand(&mut self) -> Result<i32, InsnException> {
+        let (p, ty, _) = match self.fp.iter_items(&self.mem).last() {
+            Some(item) => item?,
+            None => return Err(InsnException::ItemNotFound),
+        };
+        if ty != Type::I32 {
+            return Err(InsnException::WrongType);
+        }
+        let val = self.mem.try_load_u32(p.0 + 4)?;
+        self.set_size(p.0 - self.fp.try_body_offset(0)?);
+        Ok(val as i32)
+    }
+
+    /// Find `id` the way `Val` does, according to the scope mode.
+    fn lookup(&self, id: ItemId) -> Result<Option<ItemPtr>, InsnException> {
+        match self.scope_mode {
+            ScopeMode::Dynamic => self.find(id),
+            ScopeMode::Lexical => self.find_lexical(id),
+        }
+    }
+
+    /// Only growing past `mem_limit` fails, so shrinking can't.
+    fn set_tos(&mut self, val: u32) -> Result<(), InsnException> {
+        if val > self.mem_limit && val > self.tos() {
+            return Err(InsnException::OutOfMemory);
+        }
+        self.mem.set_len(val as usize);
+        self.max_mem = self.max_mem.max(val);
+        Ok(())
+    }
+
+    fn enter_frame(&mut self, fp: ObjPtr) {
+        self.fp = fp;
+        self.depth += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+        if let Some(f) = &mut self.hooks.on_push {
+            f(fp, self.depth);
+        }
+    }
+
+    fn set_cap(&mut self, val: u32) -> Result<(), InsnException> {
+        self.fp.set_cap(&mut self.mem, val);
+        let end = self.fp.try_body_offset(self.cap()?)?;
+        self.invariant(end <= self.tos(), "frame extends past top of stack")
+    }
+
+    fn set_size(&mut self, val: u32) {
+        self.items_gen += 1;
+        self.fp.set_size(&mut self.mem, val)
+    }
+
+    pub(crate) fn is_top_frame(&self) -> Result<bool, InsnException> {
+        let frame_end = self.fp.try_body_offset(self.cap()?)?;
+        self.invariant(
+            frame_end <= self.tos(), "frame extends past top of stack")?;
+        Ok(frame_end == self.tos())
+    }
+
+    /// Make room for `n` more bytes of items in the current frame, so a run
+    /// of `Def`s after it doesn't grow the frame one item at a time. As
+    /// with `Def`, only the top frame can grow. Unlike `Alloc`, this counts
+    /// room the frame already has.
+    pub fn reserve(&mut self, n: u32) -> Result<(), InsnException> {
+        if n & 0x3 != 0 {
+            return Err(InsnException::UnalignedCap);
+        }
+        let new_size = self.size()?.checked_add(n)
+            .ok_or(InsnException::SizeOverflow)?;
+        self.fp.try_body_offset(new_size)?;
+        self.ensure_space(new_size)
+    }
+
+    /// Make room for the current frame's body to hold `new_size` bytes. Only
+    /// the top frame can grow, so this is how a frame pushed with a cap of
+    /// 0 gets space for its first item. Anything buried under another frame
+    /// keeps the cap it was pushed with, zero included, and gets
+    /// `FrameFull`.
+    fn ensure_space(&mut self, new_size: u32) -> Result<(), InsnException> {
+        if new_size > self.cap()? {
+            if self.is_top_frame()? {
+                self.set_tos(self.fp.try_body_offset(new_size)?)?;
+                self.set_cap(new_size)
+            } else {
+                Err(InsnException::FrameFull)
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn step(&mut self) -> Result<Option<XData>, Fault> {
+        if let Some(mut hook) = self.hooks.on_step.take() {
+            // If the fetch fails, the step itself will fault.
+            let flow = self.fetch(self.pc).map(|insn| hook(&StepInfo {
+                pc: self.pc,
+                insn,
+                x: self.x,
+                fp: self.fp,
+            }));
+            self.hooks.on_step = Some(hook);
+            if let Ok(ControlFlow::Break(())) = flow {
+                let kind = InsnException::Interrupted;
+                return Err(Fault { pc: self.pc, kind });
+            }
+        }
+        #[cfg(feature = "trace-events")]
+        let before = self.hooks.on_trace.as_ref()
+            .map(|_| trace::Before::capture(self));
+        self.steps += 1;
+        let old_pc = self.pc;
+        let r = self.exec().map_err(|kind| Fault { pc: old_pc, kind });
+        #[cfg(feature = "trace-events")]
+        if let Some(b) = before {
+            let mut sink = self.hooks.on_trace.take().unwrap();
+            trace::step(self, b, &r, &mut sink);
+            self.hooks.on_trace = Some(sink);
+        }
+        r
+    }
+
+    /// Run until `pc == target_pc` (without executing the instruction
+    /// there), the program halts, or `max_steps` instructions have run.
+    pub fn run_to(&mut self, target_pc: u32, max_steps: u64)
+        -> Result<StopReason, Fault>
+    {
+        match self.run_while(|m| m.pc != target_pc, max_steps)? {
+            StopReason::Predicate => Ok(StopReason::ReachedPc),
+            r => Ok(r),
+        }
+    }
+
+    /// Run until `id` is defined in the current frame, the program halts, or
+    /// `max_steps` instructions have run.
+    pub fn run_until_defined(&mut self, id: ItemId, max_steps: u64)
+        -> Result<StopReason, Fault>
+    {
+        let mut found = None;
+        let r = self.run_while(|m| {
+            found = m.find_in_frame(m.fp, id).ok().flatten();
+            found.is_none()
+        }, max_steps)?;
+        match r {
+            StopReason::Predicate => Ok(StopReason::Defined(found.unwrap())),
+            r => Ok(r),
+        }
+    }
+
+    /// Step until the program halts, for at most `max_steps` instructions,
+    /// and return what it halted with and how many steps that took.
+    pub fn run(&mut self, max_steps: u64) -> Result<(XData, u64), RunError> {
+        for n in 1..=max_steps {
+            if let Some(x) = self.step().map_err(RunError::Fault)? {
+                return Ok((x, n));
+            }
+        }
+        Err(RunError::StepLimitExceeded)
+    }
+
+    /// Step as long as `pred` holds, checking it before each instruction,
+    /// for at most `max_steps` instructions. Returns `Predicate` once it
+    /// doesn't hold.
+    pub fn run_while(
+        &mut self, mut pred: impl FnMut(&Machine) -> bool, max_steps: u64,
+    ) -> Result<StopReason, Fault> {
+        for _ in 0..max_steps {
+            if !pred(self) {
+                return Ok(StopReason::Predicate);
+            }
+            if let Some(x) = self.step()? {
+                return Ok(StopReason::Halted(x));
+            }
+        }
+        if pred(self) {
+            Ok(StopReason::StepLimit)
+        } else {
+            Ok(StopReason::Predicate)
+        }
+    }
+
+    /// What `step` would do, without doing it. The step runs against a
+    /// throwaway clone of the machine, which has no callbacks, so this
+    /// costs a copy of memory.
+    pub fn peek_step(&self) -> Result<StepEffect, Fault> {
+        let mut m = self.clone();
+        let halted = m.step()?;
+
+        let old = self.mem.as_bytes();
+        let new = m.mem.as_bytes();
+        let word = |b: &[u8], a: usize| {
+            if a < b.len() {
+                u32::from_le_bytes(b[a..a+4].try_into().unwrap())
+            } else {
+                0
+            }
+        };
+        let writes = (0..new.len()).step_by(4)
+            .map(|a| (a as u32, word(old, a), word(new, a)))
+            .filter(|&(_, o, n)| o != n)
+            .collect();
+
+        Ok(StepEffect {
+            pc: m.pc,
+            x: m.x,
+            fp: m.fp,
+            writes,
+            mem_len: m.tos(),
+            halted,
+        })
+    }
+
+    /// `step`, reporting what happened to `sink`. Each event is delivered
+    /// after the state change it describes.
+    pub fn step_events(&mut self, sink: &mut dyn FnMut(Event))
+        -> Result<Option<XData>, Fault>
+    {
+        // If this fails, so will `step`.
+        let insn = self.fetch(self.pc).ok();
+        let x = self.x;
+        let depth = self.depth;
+
+        let r = self.step()?;
+
+        if self.depth > depth {
+            sink(Event::FramePushed(self.fp));
+        } else if self.depth < depth {
+            sink(Event::FramePopped);
+        }
+        match insn {
+            Some(Insn::Def(id)) if id != 0 => {
+                sink(Event::ItemDefined(ItemId(id), x.ty()));
+            },
+            Some(Insn::Jump(n))
+                if n & JUMP_IF_ZERO == 0 || matches!(x, XData::I32(0)) =>
+            {
+                sink(Event::Jumped(self.pc));
+            },
+            _ => (),
+        }
+        if let Some(x) = r {
+            sink(Event::Halted(x));
+        }
+        Ok(r)
+    }
+
+    fn exec(&mut self) -> Result<Option<XData>, InsnException> {
+        if self.stack_empty {
+            return Err(InsnException::StackUnderflow);
+        }
+        let insn = self.fetch(self.pc)?;
+
+        let old_pc = self.pc;
+
+        match insn {
+            Insn::Def(id) => {
+                let id = ItemId(id);
+                if id == ItemId(0) {
+                    return Ok(Some(self.x));
+                }
+                if let Some(item) = self.find_in_frame(self.fp, id)? {
+                    return Err(InsnException::ItemExistsInFrame(item));
+                }
+
+                let ty = self.x.ty();
+                // The value: one word, or a copy of an object's header and
+                // whole body, taken before the frame grows in case the
+                // object is the frame itself.
+                let value = match self.x {
+                    XData::BuiltinCode(n) | XData::Code(n) | XData::I32(n) =>
+                        n.to_le_bytes().to_vec(),
+                    XData::Object(obj) => {
+                        let cap = obj.try_cap(&self.mem)?;
+                        let end = obj.try_body_offset(cap)?;
+                        self.mem.as_bytes()
+                            .get(obj.addr() as usize .. end as usize)
+                            .ok_or(InsnException::OutOfBounds(end))?
+                            .to_vec()
+                    },
+                };
+                // `value` came out of `mem`, so its length fits in a u32.
+                let size_delta = (value.len() as u32).checked_add(4)
+                    .ok_or(InsnException::SizeOverflow)?;
+                let old_size = self.size()?;
+                let new_size = old_size.checked_add(size_delta)
+                    .ok_or(InsnException::SizeOverflow)?;
+                self.fp.try_body_offset(new_size)?;
+
+                self.ensure_space(new_size)?;
+                let item = self.fp.body_offset(old_size);
+                self.store_u32(item, item_header_to_u32(ty, id))?;
+                for (i, w) in value.chunks_exact(4).enumerate() {
+                    let w = u32::from_le_bytes(w.try_into().unwrap());
+                    self.store_u32(item + 4 + 4*i as u32, w)?;
+                }
+                if ty == Type::Object {
+                    // The copy belongs to this frame now. Objects nested in
+                    // it are copied as they are.
+                    let copy = ObjPtr::at(item + 4);
+                    copy.set_kind(&mut self.mem, FrameKind::Object);
+                    copy.set_prev(&mut self.mem, Some(self.fp));
+                    copy.set_ret(&mut self.mem, None);
+                }
+
+                self.set_size(new_size);
+
+                self.pc += 4;
+            },
+            Insn::Set(id) => {
+                // Only words can be overwritten in place; an object item
+                // owns its body, so replacing it isn't a one-word store.
+                let item = self.lookup(ItemId(id))?
+                    .ok_or(InsnException::ItemNotFound)?;
+                let ty = self.item_type(item)?;
+                let val = match self.x {
+                    XData::BuiltinCode(n) | XData::Code(n) | XData::I32(n)
+                        if self.x.ty() == ty => n,
+                    _ => return Err(InsnException::WrongType),
+                };
+                self.store_u32(item.0 + 4, val)?;
+
+                self.pc += 4;
+            },
+            Insn::Push(id) => {
+                let id = ItemId(id);
+                match self.x {
+                    XData::I32(xv) => {
+                        if self.fp.try_body_offset(self.cap()?)? != self.tos() {
+                            return Err(InsnException::NotTopFrame);
+                        }
+                        if xv & 0x3 != 0 {
+                            return Err(InsnException::UnalignedCap);
+                        }
+
+                        if id == ItemId(0) {
+                            self.push_frame(xv, None)?;
+                        } else {
+                            // Push new named object. Check the whole range
+                            // it'll occupy before touching anything, and
+                            // undo the growth if the first store fails, so a
+                            // fault leaves the machine as it was.
+                            let old_size = self.size()?;
+                            let new_size = xv
+                                .checked_add(4 + OBJ_HEADER_SIZE)
+                                .and_then(|d| d.checked_add(old_size))
+                                .ok_or(InsnException::SizeOverflow)?;
+                            let new_obj_header =
+                                self.fp.try_body_offset(old_size)?;
+                            let new_obj = ObjPtr::at(new_obj_header + 4);
+                            let obj_end = new_obj.try_body_offset(xv)?;
+                            self.invariant(
+                                obj_end == self.fp.try_body_offset(new_size)?,
+                                "new object doesn't end its frame")?;
+
+                            let (old_cap, old_tos) = (self.cap()?, self.tos());
+                            self.ensure_space(new_size)?;
+                            let header = item_header_to_u32(Type::Object, id);
+                            let stored = self.store_u32(new_obj_header, header);
+                            if let Err(e) = stored {
+                                self.fp.set_cap(&mut self.mem, old_cap);
+                                self.set_tos(old_tos)?;
+                                return Err(e);
+                            }
+                            new_obj.set_cap(&mut self.mem, xv);
+                            new_obj.set_kind(&mut self.mem, FrameKind::Object);
+                            new_obj.set_size(&mut self.mem, 0);
+                            new_obj.set_base(&mut self.mem, Some(self.fp));
+                            new_obj.set_prev(&mut self.mem, Some(self.fp));
+                            new_obj.set_ret(&mut self.mem, None);
+
+                            self.set_size(new_size);
+
+                            self.enter_frame(new_obj);
+                        }
+                    },
+                    XData::Object(obj) if id == ItemId(0) => {
+                        self.enter_object(obj)?;
+                    },
+                    _ => return Err(InsnException::WrongType),
+                }
+
+                self.pc += 4;
+            },
+            Insn::Inh(Inherent::Pop) => {
+                let new_fp = match self.prev()? {
+                    Some(new_fp) => new_fp,
+                    None => {
+                        // Popping the root frame ends the program, like
+                        // `Def(0)`; only a second pop would underflow.
+                        self.stack_empty = true;
+                        return Ok(Some(self.x));
+                    },
+                };
+                // Work everything out before changing anything, so a
+                // fault leaves the machine as it was.
+                let new_pc = match self.ret()? {
+                    Some(ret) => {
+                        let ret = ret.get();
+                        if ret & 0x3 != 0 || ret >= self.code_len() {
+                            return Err(InsnException::BadReturnTarget(ret));
+                        }
+                        ret
+                    },
+                    None => self.pc + 4,
+                };
+
+                let old_fp = self.fp;
+                let old_end =
+                    old_fp.try_body_offset(old_fp.try_cap(&self.mem)?)?;
+                // A call frame starts at or past the end of the items in the
+                // frame under it; a named object is the last of those items,
+                // so it starts before their end.
+                let end = new_fp.try_body_offset(new_fp.try_size(&self.mem)?)?;
+                let is_member = end > old_fp.addr();
+                let kind = old_fp.try_kind(&self.mem)?;
+                // An object entered with `Push` can be anywhere in the
+                // stack, and leaving it leaves the stack as it was. One
+                // that's the last item of the frame under it and ends the
+                // stack looks just like a named object, and popping it
+                // that way changes nothing.
+                let entered = kind == FrameKind::Object
+                    && (!is_member || old_end != self.tos());
+                if entered {
+                    self.pc = new_pc;
+                    self.fp = new_fp;
+                    self.depth -= 1;
+                    if let Some(f) = &mut self.hooks.on_pop {
+                        f(old_fp, self.depth);
+                    }
+                    return Ok(None);
+                }
+                self.invariant(
+                    is_member == (kind == FrameKind::Object),
+                    "frame kind doesn't match its position")?;
+                let mut new_cap = new_fp.try_cap(&self.mem)?;
+                let mut new_size = None;
+                if kind == FrameKind::Object {
+                    // The object may have grown since it was pushed (only
+                    // ever from the top, so it's still last), and the frame
+                    // has to cover its whole cap, not just the part its items
+                    // use.
+                    self.invariant(
+                        end <= old_end, "member object doesn't end its frame")?;
+                    let size = old_end - new_fp.try_body_offset(0)?;
+                    new_cap = new_cap.max(size);
+                    new_size = Some(size);
+                }
+                let new_tos = new_fp.try_body_offset(new_cap)?;
+                self.invariant(
+                    new_tos <= self.tos(), "frame extends past top of stack")?;
+                // It only shrinks, so this is the last thing that can fail.
+                self.set_tos(new_tos)?;
+
+                self.pc = new_pc;
+                self.fp = new_fp;
+                self.depth -= 1;
+                self.fp.set_cap(&mut self.mem, new_cap);
+                if let Some(size) = new_size {
+                    self.set_size(size);
+                }
+                if self.compact_on_pop {
+                    self.compact();
+                }
+                if let Some(f) = &mut self.hooks.on_pop {
+                    f(old_fp, self.depth);
+                }
+            },
+            Insn::Inh(Inherent::Call) => {
+                match self.x {
+                    XData::Code(target) => {
+                        if self.fp.try_body_offset(self.cap()?)? != self.tos() {
+                            return Err(InsnException::NotTopFrame);
+                        }
+                        if target & 0x3 != 0 || target >= self.code_len() {
+                            return Err(InsnException::BadJumpTarget(target));
+                        }
+                        let ret = NonZeroU32::new(self.pc + 4);
+                        self.push_frame(0, ret)?;
+                        self.pc = target;
+                    },
+                    XData::BuiltinCode(index) => {
+                        self.call_builtin(index)?;
+                        self.pc += 4;
+                    },
+                    _ => return Err(InsnException::WrongType),
+                }
+            },
+            Insn::Inh(Inherent::Alloc) => {
+                let n = match self.x {
+                    XData::I32(n) => n,
+                    _ => return Err(InsnException::WrongType),
+                };
+                if n & 0x3 != 0 {
+                    return Err(InsnException::UnalignedCap);
+                }
+                if !self.is_top_frame()? {
+                    return Err(InsnException::NotTopFrame);
+                }
+                let new_cap = self.cap()?.checked_add(n)
+                    .ok_or(InsnException::SizeOverflow)?;
+                self.set_tos(self.fp.try_body_offset(new_cap)?)?;
+                self.set_cap(new_cap)?;
+
+                self.pc += 4;
+            },
+            Insn::Inh(Inherent::Frame) => {
+                self.x = XData::Object(self.fp);
+
+                self.pc += 4;
+            },
+            Insn::Inh(Inherent::Pc) => {
+                self.x = XData::Code(self.pc + 4);
+
+                self.pc += 4;
+            },
+            Insn::Inh(op @ Inherent::Add)
+            | Insn::Inh(op @ Inherent::Sub)
+            | Insn::Inh(op @ Inherent::Mul)
+            | Insn::Inh(op @ Inherent::Cmp) => {
+                let right = self.x.as_i32().ok_or(InsnException::WrongType)?;
+                let left = self.pop_operand()?;
+                self.x = XData::I32(match op {
+                    Inherent::Add => left.wrapping_add(right),
+                    Inherent::Sub => left.wrapping_sub(right),
+                    Inherent::Mul => left.wrapping_mul(right),
+                    _ => left.cmp(&right) as i32,
+                } as u32);
+
+                self.pc += 4;
+            },
+            Insn::Val(id) => {
+                let id = ItemId(id);
+
+                if id == ItemId(0) {
+                    self.x = XData::Object(self.gp);
+                } else {
+                    let item = self.lookup(id)?
+                        .ok_or(InsnException::ItemNotFound)?;
+                    self.x = self.try_item_value(item)?;
+                }
+
+                self.pc += 4;
+            },
+            Insn::Xlo(n) => {
+                self.x = XData::I32(n);
+
+                self.pc += 4;
+            },
+            Insn::Jump(n) => {
+                let taken = if n & JUMP_IF_ZERO == 0 {
+                    true
+                } else if let XData::I32(x) = self.x {
+                    x == 0
+                } else {
+                    return Err(InsnException::WrongType);
+                };
+                if taken {
+                    let target = insn.jump_target(self.pc).unwrap();
+                    if target & 0x3 != 0 || target >= self.code_len() {
+                        return Err(InsnException::BadJumpTarget(target));
+                    }
+                    self.pc = target;
+                } else {
+                    self.pc += 4;
+                }
+            },
+            Insn::Xhi(n) => {
+                if n & 0x7 != n {
+                    return Err(InsnException::BadImmediate(insn));
+                }
+                if let XData::I32(n2) = self.x {
+                    self.x = XData::I32((n2 & 0x1FFF_FFFF) | (n<<29));
+                } else {
+                    return Err(InsnException::WrongType);
+                }
+
+                self.pc += 4;
+            },
+            _ => return Err(InsnException::Unimplemented(insn)),
+        }
+
+        // A jump to itself is a legitimate (if pointless) loop, `Call` can
+        // call itself, and `Pop` can return to its own address.
+        let can_stay = matches!(insn, Insn::Jump(_)
+            | Insn::Inh(Inherent::Call) | Insn::Inh(Inherent::Pop));
+        self.invariant(self.pc != old_pc || can_stay, "pc didn't advance")?;
+
+        Ok(None)
+    }
+}
+
+/// Floyd's cycle detection over a chain of objects linked by `next`.
+fn has_cycle<F>(start: ObjPtr, next: F) -> Result<bool, InsnException>
+    where F: Fn(ObjPtr) -> Result<Option<ObjPtr>, InsnException>
+{
+    let mut slow = start;
+    let mut fast = start;
+    loop {
+        for _ in 0..2 {
+            fast = match next(fast)? {
+                Some(p) => p,
+                None => return Ok(false),
+            };
+            if fast == slow {
+                return Ok(true);
+            }
+        }
+        // `fast` already went through here, so there's always a next.
+        slow = next(slow)?.unwrap();
+    }
+}
@@ -0,0 +1,630 @@
+//! Memory and what's in it: the byte-addressed `Mem`, the object header
+//! layout, and pointers to objects and items.
+
+use core::convert::TryInto;
+use core::fmt;
+use core::num::NonZeroU32;
+use core::ops::Range;
+
+use crate::format::{friendly_hex_u32, Paint};
+use crate::{crc32, FormatStyle, InsnException, ItemNames};
+
+// Object header layout, version 1. Every object (frames included) starts
+// with five words:
+//
+//   +0   cap   bytes reserved for the body; since that's a multiple of 4,
+//              bit 0 holds the frame kind (see `FrameKind`)
+//   +4   size  bytes of the body in use
+//   +8   base  lexical parent, or 0
+//   +12  prev  dynamic parent (the frame to return to), or 0
+//   +16  ret   return address, or 0
+//
+// and the body follows immediately.
+pub(crate) const CAP_OFFSET: u32 = 0;
+pub(crate) const SIZE_OFFSET: u32 = 4;
+pub(crate) const BASE_OFFSET: u32 = 8;
+pub(crate) const PREV_OFFSET: u32 = 12;
+pub(crate) const RET_OFFSET: u32 = 16;
+pub(crate) const OBJ_HEADER_SIZE: u32 = 20;
+pub(crate) const CAP_KIND_BIT: u32 = 0x1;
+pub(crate) const CAP_MASK: u32 = !0x3;
+
+/// Where each header field lives, for a given object-file version.
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderLayout {
+    pub version: u8,
+    pub cap: u32,
+    pub size: u32,
+    pub base: u32,
+    pub prev: u32,
+    pub ret: u32,
+    pub header_size: u32,
+}
+
+pub const HEADER_LAYOUT_V1: HeaderLayout = HeaderLayout {
+    version: 1,
+    cap: CAP_OFFSET,
+    size: SIZE_OFFSET,
+    base: BASE_OFFSET,
+    prev: PREV_OFFSET,
+    ret: RET_OFFSET,
+    header_size: OBJ_HEADER_SIZE,
+};
+
+impl HeaderLayout {
+    pub const CURRENT: HeaderLayout = HEADER_LAYOUT_V1;
+
+    pub fn for_version(version: u8) -> Option<&'static HeaderLayout> {
+        match version {
+            1 => Some(&HEADER_LAYOUT_V1),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ItemPtr(pub(crate) u32);
+
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct ItemId(pub(crate) u32);
+
+/// `#0000_0001`
+impl fmt::Display for ItemId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", friendly_hex_u32(self.0))
+    }
+}
+
+/// `ItemId(#0000_0001)`
+impl fmt::Debug for ItemId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ItemId({})", self)
+    }
+}
+
+impl ItemId {
+    /// Ids are 29 bits wide. Id 0 is special: defining it returns from the
+    /// current call, or halts the machine at the top level.
+    pub fn new(n: u32) -> Result<ItemId, &'static str> {
+        if n & 0xE000_0000 == 0 {
+            Ok(ItemId(n))
+        } else {
+            Err("item id doesn't fit in bits 28..0")
+        }
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+/// What made a frame. Set when it's pushed and never changed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameKind {
+    /// `Push(0)` or `Call`: the frame sits above its parent's body. The
+    /// root frame counts as one.
+    Call,
+    /// `Push` with a nonzero id: the frame is an item in its parent's body.
+    Object,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ObjPtr(NonZeroU32);
+
+impl ObjPtr {
+    /// A pointer to an object header at `addr`, which must be nonzero,
+    /// word-aligned, and leave room for the header below 4 GiB. Whether
+    /// there's really an object there is up to the caller.
+    pub fn new(addr: u32) -> Result<ObjPtr, &'static str> {
+        if addr == 0 {
+            Err("object at address 0")
+        } else if addr & 0x3 != 0 {
+            Err("object address isn't word-aligned")
+        } else if addr.checked_add(OBJ_HEADER_SIZE).is_none() {
+            Err("object header runs past the end of the address space")
+        } else {
+            Ok(ObjPtr::at(addr))
+        }
+    }
+
+    /// Decode a stored pointer field, where 0 means "none".
+    pub(crate) fn from_u32(p: u32) -> Option<ObjPtr> {
+        NonZeroU32::new(p).map(ObjPtr)
+    }
+
+    /// For addresses that are nonzero by construction.
+    pub(crate) fn at(p: u32) -> ObjPtr {
+        ObjPtr(NonZeroU32::new(p).expect("object at address 0"))
+    }
+
+    pub fn addr(&self) -> u32 {
+        self.0.get()
+    }
+
+    pub(crate) fn body_offset(&self, n: u32) -> u32 {
+        self.addr() + OBJ_HEADER_SIZE + n
+    }
+
+    pub(crate) fn try_body_offset(&self, n: u32) -> Result<u32, InsnException> {
+        self.addr().checked_add(OBJ_HEADER_SIZE)
+            .and_then(|a| a.checked_add(n))
+            .ok_or(InsnException::SizeOverflow)
+    }
+
+    pub fn cap(&self, mem: &Mem) -> u32 {
+        mem.load_u32(self.addr() + CAP_OFFSET) & CAP_MASK
+    }
+
+    pub fn kind(&self, mem: &Mem) -> FrameKind {
+        if mem.load_u32(self.addr() + CAP_OFFSET) & CAP_KIND_BIT == 0 {
+            FrameKind::Call
+        } else {
+            FrameKind::Object
+        }
+    }
+
+    pub fn size(&self, mem: &Mem) -> u32 {
+        mem.load_u32(self.addr() + SIZE_OFFSET)
+    }
+
+    pub fn base(&self, mem: &Mem) -> Option<ObjPtr> {
+        ObjPtr::from_u32(mem.load_u32(self.addr() + BASE_OFFSET))
+    }
+
+    pub fn prev(&self, mem: &Mem) -> Option<ObjPtr> {
+        ObjPtr::from_u32(mem.load_u32(self.addr() + PREV_OFFSET))
+    }
+
+    pub fn ret(&self, mem: &Mem) -> Option<NonZeroU32> {
+        NonZeroU32::new(mem.load_u32(self.addr() + RET_OFFSET))
+    }
+
+    pub fn try_cap(&self, mem: &Mem) -> Result<u32, InsnException> {
+        Ok(mem.try_load_u32(self.addr() + CAP_OFFSET)? & CAP_MASK)
+    }
+
+    pub fn try_kind(&self, mem: &Mem) -> Result<FrameKind, InsnException> {
+        mem.try_load_u32(self.addr() + CAP_OFFSET)?;
+        Ok(self.kind(mem))
+    }
+
+    pub fn try_size(&self, mem: &Mem) -> Result<u32, InsnException> {
+        mem.try_load_u32(self.addr() + SIZE_OFFSET)
+    }
+
+    pub fn try_base(&self, mem: &Mem)
+        -> Result<Option<ObjPtr>, InsnException>
+    {
+        let p = mem.try_load_u32(self.addr() + BASE_OFFSET)?;
+        Ok(ObjPtr::from_u32(p))
+    }
+
+    pub fn try_prev(&self, mem: &Mem)
+        -> Result<Option<ObjPtr>, InsnException>
+    {
+        let p = mem.try_load_u32(self.addr() + PREV_OFFSET)?;
+        Ok(ObjPtr::from_u32(p))
+    }
+
+    pub fn try_ret(&self, mem: &Mem)
+        -> Result<Option<NonZeroU32>, InsnException>
+    {
+        Ok(NonZeroU32::new(mem.try_load_u32(self.addr() + RET_OFFSET)?))
+    }
+
+    /// Check the header against the rest of memory and make sure the items
+    /// tile the body. Only the top frame has to end exactly at
+    /// `body_offset(size)`; a frame with a member object still on the stack
+    /// can have a short size (see `ObjPtr::write`).
+    pub fn validate(&self, mem: &Mem) -> Result<(), InsnException> {
+        let corrupt = |why| InsnException::CorruptFrame(*self, why);
+        let len = mem.len() as u64;
+        let body = self.addr() as u64 + OBJ_HEADER_SIZE as u64;
+
+        let cap = self.try_cap(mem)?;
+        let size = self.try_size(mem)?;
+        if size > cap {
+            return Err(corrupt("size exceeds cap"));
+        }
+        if body + cap as u64 > len {
+            return Err(corrupt("cap extends past end of memory"));
+        }
+
+        let end = body + size as u64;
+        let mut p = body;
+        while p < end {
+            let (ty, _) = try_item_header_from_u32(mem.try_load_u32(p as u32)?)
+                .ok_or_else(|| corrupt("bad item type"))?;
+            p += 4 + match ty {
+                Type::BuiltinCode => 4,
+                Type::Code => 4,
+                Type::I32 => 4,
+                Type::Object => {
+                    let obj = ObjPtr::at(p as u32 + 4);
+                    OBJ_HEADER_SIZE as u64 + obj.try_cap(mem)? as u64
+                },
+            };
+        }
+        if p > len {
+            return Err(corrupt("item extends past end of memory"));
+        }
+        let is_top = body + cap as u64 == len;
+        if is_top && p != end {
+            return Err(corrupt("items don't end at size"));
+        }
+        Ok(())
+    }
+
+    /// Keeps the frame kind. `val` must be a multiple of 4.
+    pub fn set_cap(&self, mem: &mut Mem, val: u32) {
+        let kind = mem.load_u32(self.addr() + CAP_OFFSET) & !CAP_MASK;
+        mem.store_u32(self.addr() + CAP_OFFSET, val | kind);
+    }
+
+    /// Only for a freshly pushed frame.
+    pub fn set_kind(&self, mem: &mut Mem, kind: FrameKind) {
+        let cap = self.cap(mem);
+        let bit = match kind {
+            FrameKind::Call => 0,
+            FrameKind::Object => CAP_KIND_BIT,
+        };
+        mem.store_u32(self.addr() + CAP_OFFSET, cap | bit);
+    }
+
+    pub fn set_size(&self, mem: &mut Mem, val: u32) {
+        mem.store_u32(self.addr() + SIZE_OFFSET, val);
+    }
+
+    pub fn set_base(&self, mem: &mut Mem, val: Option<ObjPtr>) {
+        let p = val.map_or(0, |p| p.addr());
+        mem.store_u32(self.addr() + BASE_OFFSET, p);
+    }
+
+    pub fn set_prev(&self, mem: &mut Mem, val: Option<ObjPtr>) {
+        let p = val.map_or(0, |p| p.addr());
+        mem.store_u32(self.addr() + PREV_OFFSET, p);
+    }
+
+    pub fn set_ret(&self, mem: &mut Mem, val: Option<NonZeroU32>) {
+        let r = val.map_or(0, NonZeroU32::get);
+        mem.store_u32(self.addr() + RET_OFFSET, r);
+    }
+
+    /// List the items, one line each. A bad item ends the list with the
+    /// exception reading it raised.
+    pub fn write(&self, mem: &Mem, w: &mut impl fmt::Write) -> fmt::Result {
+        self.write_with(mem, w, FormatStyle::default())
+    }
+
+    pub fn write_with(
+        &self,
+        mem: &Mem,
+        w: &mut impl fmt::Write,
+        style: FormatStyle,
+    ) -> fmt::Result {
+        self.write_impl(mem, w, style, Paint(false), &ItemNames::new())
+    }
+
+    pub(crate) fn write_impl(
+        &self,
+        mem: &Mem,
+        w: &mut impl fmt::Write,
+        style: FormatStyle,
+        paint: Paint,
+        names: &ItemNames,
+    ) -> fmt::Result {
+        // The last item can end past the size, because frames (except for
+        // the top one) can have a short cap and size if we're in a frame
+        // created via `push "foo"`. `iter_items` allows for that.
+        for item in self.iter_items(mem) {
+            match item {
+                Ok((_, ty, id)) => {
+                    let ty_name = paint.ty(format!("{:?}", ty));
+                    let id = names.describe(id, style);
+                    writeln!(w, "  id {}: {}", id, ty_name)?;
+                },
+                Err(e) => writeln!(w, "  {:?}", e)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk the items in the body, up to `size`. An item that ends past
+    /// `size` is still yielded, since a frame whose member object is on the
+    /// stack above it has a short size (see `ObjPtr::write`). The walk
+    /// ends after the first error.
+    pub fn iter_items<'a>(&self, mem: &'a Mem) -> Items<'a> {
+        let body = self.addr() as u64 + OBJ_HEADER_SIZE as u64;
+        let end = self.try_size(mem).and_then(|n| self.try_body_offset(n));
+        let (end, err) = match end {
+            Ok(end) => (end as u64, None),
+            Err(e) => (body, Some(e)),
+        };
+        Items { mem, obj: *self, next: body, end, err }
+    }
+}
+
+/// The items in an object, from `ObjPtr::iter_items`.
+pub struct Items<'a> {
+    mem: &'a Mem,
+    obj: ObjPtr,
+    pub(crate) next: u64,
+    pub(crate) end: u64,
+    err: Option<InsnException>,
+}
+
+impl<'a> Items<'a> {
+    /// The item at `p` and how many bytes its value takes.
+    fn item(&self, p: u64)
+        -> Result<((ItemPtr, Type, ItemId), u64), InsnException>
+    {
+        // `p` is below `end`, which fits.
+        let p = p as u32;
+        let (ty, id) = try_item_header_from_u32(self.mem.try_load_u32(p)?)
+            .ok_or(InsnException::CorruptFrame(self.obj, "bad item type"))?;
+        let len = match ty {
+            Type::BuiltinCode | Type::Code | Type::I32 => 4,
+            Type::Object => {
+                let cap = ObjPtr::at(p + 4).try_cap(self.mem)?;
+                OBJ_HEADER_SIZE as u64 + cap as u64
+            },
+        };
+        Ok(((ItemPtr(p), ty, id), len))
+    }
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = Result<(ItemPtr, Type, ItemId), InsnException>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.err.take() {
+            return Some(Err(e));
+        }
+        if self.next >= self.end {
+            return None;
+        }
+        match self.item(self.next) {
+            Ok((item, len)) => {
+                self.next += 4 + len;
+                Some(Ok(item))
+            },
+            Err(e) => {
+                self.next = self.end;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum XData {
+    BuiltinCode(u32), // index (0 is reserved for future extensions)
+    Code(u32), // pointer
+    I32(u32),
+    Object(ObjPtr),
+}
+
+impl XData {
+    pub fn ty(&self) -> Type {
+        match *self {
+            XData::BuiltinCode(_) => Type::BuiltinCode,
+            XData::Code(_) => Type::Code,
+            XData::I32(_) => Type::I32,
+            XData::Object(_) => Type::Object,
+        }
+    }
+
+    /// The `I32` value, reinterpreted as signed.
+    pub fn as_i32(&self) -> Option<i32> {
+        match *self {
+            XData::I32(n) => Some(n as i32),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<ObjPtr> {
+        match *self {
+            XData::Object(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    pub fn as_code(&self) -> Option<u32> {
+        match *self {
+            XData::Code(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    pub fn as_builtin(&self) -> Option<u32> {
+        match *self {
+            XData::BuiltinCode(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Type {
+    BuiltinCode,
+    Code,
+    I32,
+    Object,
+}
+
+pub fn item_header_from_u32(h: u32) -> (Type, ItemId) {
+    try_item_header_from_u32(h).expect("bad item type")
+}
+
+/// `None` if the type bits don't name a type.
+pub fn try_item_header_from_u32(h: u32) -> Option<(Type, ItemId)> {
+    use Type::*;
+    let ty = h >> 29;
+    let id = h & 0x1FFF_FFFF;
+    Some((match ty {
+        0 => BuiltinCode,
+        1 => Code,
+        2 => I32,
+        3 => Object,
+        _ => return None,
+    }, ItemId(id)))
+}
+
+pub const fn item_header_to_u32(ty: Type, id: ItemId) -> u32 {
+    use Type::*;
+    let ty = match ty {
+        BuiltinCode => 0,
+        Code => 1,
+        I32 => 2,
+        Object => 3,
+    };
+    (ty<<29) | id.0
+}
+
+/// Byte-addressed memory. The backing `Vec` grows by doubling and is
+/// always zero past `len`, so growing the logical length within capacity
+/// costs nothing and fresh memory reads as zero.
+#[derive(Clone)]
+pub struct Mem {
+    pub(crate) bytes: Vec<u8>,
+    len: usize,
+    /// Only there if the machine was built with `log_writes`.
+    pub(crate) log: Option<Box<WriteLog>>,
+}
+
+/// `(addr, old, new)` for every word stored to memory, oldest first, up to
+/// a fixed number of entries.
+#[derive(Clone, Debug, Default)]
+pub struct WriteLog {
+    entries: Vec<(u32, u32, u32)>,
+    max: usize,
+    truncated: bool,
+}
+
+impl WriteLog {
+    /// An empty log that keeps at most `max` entries.
+    pub(crate) fn new(max: usize) -> Self {
+        WriteLog { max, ..WriteLog::default() }
+    }
+
+    pub fn entries(&self) -> &[(u32, u32, u32)] {
+        &self.entries
+    }
+
+    /// Whether later writes were dropped because the log was full.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    fn record(&mut self, addr: u32, old: u32, new: u32) {
+        if self.entries.len() < self.max {
+            self.entries.push((addr, old, new));
+        } else {
+            self.truncated = true;
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.truncated = false;
+    }
+}
+
+impl Mem {
+    pub(crate) fn from_vec(bytes: Vec<u8>) -> Self {
+        let len = bytes.len();
+        Self { bytes, len, log: None }
+    }
+
+    pub(crate) fn into_vec(mut self) -> Vec<u8> {
+        self.bytes.truncate(self.len);
+        self.bytes
+    }
+
+    /// The logical size in bytes; everything at or past it is out of
+    /// bounds.
+    pub fn len(&self) -> u32 {
+        self.len as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Grow or shrink to `len` bytes. Growth past the backing `Vec` at
+    /// least doubles it; shrinking zeroes what it drops.
+    pub(crate) fn set_len(&mut self, len: usize) {
+        if len > self.bytes.len() {
+            let phys = len.max(2*self.bytes.len());
+            self.bytes.resize(phys, 0);
+        } else if len < self.len {
+            for b in &mut self.bytes[len..self.len] {
+                *b = 0;
+            }
+        }
+        self.len = len;
+    }
+
+    pub(crate) fn extend_from_slice(&mut self, b: &[u8]) {
+        let start = self.len;
+        self.set_len(start + b.len());
+        self.bytes[start..self.len].copy_from_slice(b);
+    }
+
+    /// Free the backing storage past `len`.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.bytes.truncate(self.len);
+        self.bytes.shrink_to_fit();
+    }
+
+    /// CRC-32 over every live byte.
+    pub fn checksum(&self) -> u32 {
+        crc32(self.as_bytes())
+    }
+
+    /// For addresses known to be in bounds. Panics otherwise.
+    pub fn load_u32(&self, addr: u32) -> u32 {
+        match self.try_load_u32(addr) {
+            Ok(val) => val,
+            Err(_) => panic!(
+                "load from #{} is out of bounds", friendly_hex_u32(addr)),
+        }
+    }
+
+    pub fn try_load_u32(&self, addr: u32) -> Result<u32, InsnException> {
+        let range = self.word(addr)?;
+        Ok(u32::from_le_bytes(self.bytes[range].try_into().unwrap()))
+    }
+
+    /// For addresses known to be in bounds. Panics otherwise.
+    pub fn store_u32(&mut self, addr: u32, val: u32) {
+        if self.try_store_u32(addr, val).is_err() {
+            panic!("store to #{} is out of bounds", friendly_hex_u32(addr));
+        }
+    }
+
+    pub fn try_store_u32(&mut self, addr: u32, val: u32)
+        -> Result<(), InsnException>
+    {
+        let range = self.word(addr)?;
+        if let Some(mut log) = self.log.take() {
+            log.record(addr, self.load_u32(addr), val);
+            self.log = Some(log);
+        }
+        self.bytes[range].copy_from_slice(&val.to_le_bytes());
+        Ok(())
+    }
+
+    /// The bytes of the word at `addr`, if it's all below `len`.
+    fn word(&self, addr: u32) -> Result<Range<usize>, InsnException> {
+        match addr.checked_add(4) {
+            Some(end) if end as usize <= self.len =>
+                Ok(addr as usize .. end as usize),
+            _ => Err(InsnException::OutOfBounds(addr)),
+        }
+    }
+}
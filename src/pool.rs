@@ -0,0 +1,51 @@
+//! Recycling memory across many short runs.
+
+use crate::{Fault, Machine, StopReason};
+
+/// Keeps the memory of finished machines so the next run can reuse its
+/// capacity instead of allocating.
+///
+/// A pool is `Send` but `run` takes `&mut self`, so share one between
+/// threads behind a `Mutex`, or better, give each thread its own.
+#[derive(Default)]
+pub struct MachinePool {
+    free: Vec<Vec<u8>>,
+}
+
+impl MachinePool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `code` on a default machine until it halts or faults, then keep
+    /// its memory for next time.
+    pub fn run(&mut self, code: &[u32]) -> Result<StopReason, Fault> {
+        let mut m = self.machine(code);
+        let r = m.run_while(|_| true, u64::MAX);
+        self.recycle(m);
+        r
+    }
+
+    /// A machine for `code` whose memory comes from the pool if it can.
+    /// Hand it back with `recycle` when done.
+    pub fn machine(&mut self, code: &[u32]) -> Machine {
+        let mut mem = self.free.pop().unwrap_or_default();
+        mem.reserve(4*code.len() + 0x100);
+        for w in code {
+            mem.extend_from_slice(&w.to_le_bytes());
+        }
+        Machine::builder(&[]).build_with(mem)
+    }
+
+    /// Keep `m`'s memory (emptied, but with its capacity) for a later run.
+    pub fn recycle(&mut self, m: Machine) {
+        let mut mem = m.mem.into_vec();
+        mem.clear();
+        self.free.push(mem);
+    }
+
+    /// How many buffers are waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.free.len()
+    }
+}
@@ -0,0 +1,225 @@
+//! Drive a machine from another process over TCP.
+//!
+//! Wire format, version 1. All integers are little-endian. Every message in
+//! either direction is a `u32` byte count followed by that many bytes of
+//! payload. One connection holds one machine, and requests are answered in
+//! order.
+//!
+//! A request payload is `[version, command, args...]`:
+//!
+//! ```text
+//! 0 Load       object file bytes (see `ObjectFile`)
+//! 1 Step
+//! 2 Run        u64 max steps, capped at `MAX_RUN_STEPS`
+//! 3 Snapshot
+//! 4 StackDump
+//! ```
+//!
+//! A response payload is `[version, status, body...]`:
+//!
+//! ```text
+//! status 0 (ok)     body depends on the command, below
+//! status 1 (error)  UTF-8 message
+//! status 2 (fault)  u32 pc, then the exception as UTF-8 text
+//! ```
+//!
+//! Ok bodies:
+//!
+//! ```text
+//! Load       empty
+//! Step, Run  u32 pc, then 0 if still running, or 1 and an xdata if halted
+//! Snapshot   u32 pc, u32 fp, u32 gp, xdata x, u32 mem length, mem bytes
+//! StackDump  UTF-8 text, as from `Machine::write_stack`
+//! ```
+//!
+//! Loaded code has to pass `verify`, and the machine runs in strict mode
+//! with at most `MAX_MEM` bytes of memory, so a bad program faults instead
+//! of taking the server down.
+//!
+//! An xdata is a type byte (numbered as in item headers: 0 builtin code,
+//! 1 code, 2 i32, 3 object) and a `u32` (the object's address for objects).
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::{verify, Fault, Machine, ObjectFile, StopReason, XData};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+const CMD_LOAD: u8 = 0;
+const CMD_STEP: u8 = 1;
+const CMD_RUN: u8 = 2;
+const CMD_SNAPSHOT: u8 = 3;
+const CMD_STACK_DUMP: u8 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+const STATUS_FAULT: u8 = 2;
+
+/// Messages bigger than this are refused rather than buffered.
+const MAX_MESSAGE: u32 = 64 << 20;
+
+/// A loaded program's memory limit (see `MachineBuilder::mem_limit`).
+pub const MAX_MEM: u32 = 64 << 20;
+
+/// The most steps one `Run` takes, so one request can't hold the server
+/// forever. A program that's still running can be run again.
+pub const MAX_RUN_STEPS: u64 = 1 << 20;
+
+/// Accept connections on `addr` forever, serving one at a time. A
+/// connection that fails is passed to `on_error` and dropped, and the
+/// server goes on to the next one.
+pub fn serve(addr: &str, mut on_error: impl FnMut(io::Error))
+    -> io::Result<()>
+{
+    let listener = TcpListener::bind(addr)?;
+    for stream in listener.incoming() {
+        if let Err(e) = handle(stream?) {
+            on_error(e);
+        }
+    }
+    Ok(())
+}
+
+/// Serve requests on one connection until the peer hangs up.
+pub fn handle(mut stream: TcpStream) -> io::Result<()> {
+    let mut m = None;
+    while let Some(req) = read_message(&mut stream)? {
+        let resp = respond(&mut m, &req);
+        write_message(&mut stream, &resp)?;
+    }
+    Ok(())
+}
+
+/// Read one length-prefixed message, or `None` at a clean end of stream.
+pub fn read_message(r: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData, "message too long"));
+    }
+    // Only as much as actually arrives gets allocated, whatever the header
+    // claims.
+    let mut buf = Vec::new();
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof, "message cut short"));
+    }
+    Ok(Some(buf))
+}
+
+pub fn write_message(w: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+fn respond(m: &mut Option<Machine>, req: &[u8]) -> Vec<u8> {
+    match dispatch(m, req) {
+        Ok(body) => reply(STATUS_OK, &body),
+        Err(Reply::Error(msg)) => reply(STATUS_ERROR, msg.as_bytes()),
+        Err(Reply::Fault(Fault { pc, kind })) => {
+            let mut body = pc.to_le_bytes().to_vec();
+            body.extend_from_slice(kind.to_string().as_bytes());
+            reply(STATUS_FAULT, &body)
+        },
+    }
+}
+
+fn reply(status: u8, body: &[u8]) -> Vec<u8> {
+    let mut resp = vec![PROTOCOL_VERSION, status];
+    resp.extend_from_slice(body);
+    resp
+}
+
+enum Reply {
+    Error(String),
+    Fault(Fault),
+}
+
+fn dispatch(m: &mut Option<Machine>, req: &[u8]) -> Result<Vec<u8>, Reply> {
+    let (cmd, args) = match *req {
+        [PROTOCOL_VERSION, cmd, ref args @ ..] => (cmd, args),
+        [v, ..] => {
+            return Err(Reply::Error(format!("unsupported version {}", v)));
+        },
+        [] => return Err(Reply::Error("empty request".to_string())),
+    };
+    if cmd == CMD_LOAD {
+        let obj = ObjectFile::from_bytes(args)
+            .map_err(|e| Reply::Error(e.to_string()))?;
+        verify(&obj.code).map_err(|e| Reply::Error(e.to_string()))?;
+        let new = Machine::builder(&[])
+            .strict(true)
+            .mem_limit(MAX_MEM)
+            .build_from_object(&obj, 0)
+            .map_err(|e| Reply::Error(e.to_string()))?;
+        *m = Some(new);
+        return Ok(Vec::new());
+    }
+    let m = m.as_mut()
+        .ok_or_else(|| Reply::Error("no program loaded".to_string()))?;
+    let mut body = Vec::new();
+    match cmd {
+        CMD_STEP => {
+            let halted = m.step().map_err(Reply::Fault)?;
+            put_progress(&mut body, m.pc, halted);
+        },
+        CMD_RUN => {
+            let max_steps = args.try_into().map(u64::from_le_bytes)
+                .map_err(|_| Reply::Error("Run takes a u64".to_string()))?;
+            let max_steps = max_steps.min(MAX_RUN_STEPS);
+            let halted = match m.run_while(|_| true, max_steps) {
+                Ok(StopReason::Halted(x)) => Some(x),
+                Ok(_) => None,
+                Err(f) => return Err(Reply::Fault(f)),
+            };
+            put_progress(&mut body, m.pc, halted);
+        },
+        CMD_SNAPSHOT => {
+            body.extend_from_slice(&m.pc.to_le_bytes());
+            body.extend_from_slice(&m.fp.addr().to_le_bytes());
+            body.extend_from_slice(&m.gp.addr().to_le_bytes());
+            put_xdata(&mut body, m.x);
+            body.extend_from_slice(&m.mem.len().to_le_bytes());
+            body.extend_from_slice(m.mem.as_bytes());
+        },
+        CMD_STACK_DUMP => {
+            let mut s = String::new();
+            m.write_stack(&mut s).unwrap();
+            body.extend_from_slice(s.as_bytes());
+        },
+        _ => return Err(Reply::Error(format!("unknown command {}", cmd))),
+    }
+    Ok(body)
+}
+
+fn put_progress(body: &mut Vec<u8>, pc: u32, halted: Option<XData>) {
+    body.extend_from_slice(&pc.to_le_bytes());
+    match halted {
+        None => body.push(0),
+        Some(x) => {
+            body.push(1);
+            put_xdata(body, x);
+        },
+    }
+}
+
+fn put_xdata(body: &mut Vec<u8>, x: XData) {
+    let (ty, n) = match x {
+        XData::BuiltinCode(n) => (0, n),
+        XData::Code(p) => (1, p),
+        XData::I32(n) => (2, n),
+        XData::Object(obj) => (3, obj.addr()),
+    };
+    body.push(ty);
+    body.extend_from_slice(&n.to_le_bytes());
+}
@@ -0,0 +1,109 @@
+use crate::{
+    Fault, InsnException, ItemId, Machine, ObjPtr, StopReason, XData,
+};
+
+/// An item's value with every pointer resolved, so it stays meaningful after
+/// the machine moves on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    BuiltinCode(u32),
+    Code(u32),
+    I32(u32),
+    Object(Vec<(ItemId, Value)>),
+}
+
+/// Owns a `Machine` and only hands out `Value`s, never raw addresses.
+pub struct Session {
+    pub(crate) m: Machine,
+    result: Option<Value>,
+}
+
+impl Session {
+    pub fn new(code: &[u32]) -> Self {
+        Self { m: Machine::new(code), result: None }
+    }
+
+    /// Step until the program halts or `max_steps` instructions have run.
+    /// Returns `Ok(false)` if the budget ran out first; calling `run` again
+    /// resumes where it stopped.
+    pub fn run(&mut self, max_steps: u64) -> Result<bool, Fault> {
+        if self.result.is_some() {
+            return Ok(true);
+        }
+        match self.m.run_while(|_| true, max_steps)? {
+            StopReason::Halted(x) => {
+                let v = self.value(x).map_err(|kind| {
+                    Fault { pc: self.m.pc(), kind }
+                })?;
+                self.result = Some(v);
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    pub fn result(&self) -> Option<&Value> {
+        self.result.as_ref()
+    }
+
+    pub fn locals(&self) -> Result<Vec<(ItemId, Value)>, InsnException> {
+        self.items(self.m.fp)
+    }
+
+    pub fn globals(&self) -> Result<Vec<(ItemId, Value)>, InsnException> {
+        self.items(self.m.gp)
+    }
+
+    pub fn stack_dump(&self) -> Result<String, InsnException> {
+        let mut s = String::new();
+        for (depth, f) in self.m.frames().enumerate() {
+            s += &format!("frame {}:\n", depth);
+            for (id, val) in self.items(f)? {
+                s += &format!("  id {}: {:?}\n", id, val);
+            }
+        }
+        Ok(s)
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.m.pc()
+    }
+
+    /// Number of frames above the root.
+    pub fn frame_depth(&self) -> u32 {
+        self.m.frame_depth()
+    }
+
+    /// Highest `frame_depth` seen so far.
+    pub fn max_frame_depth(&self) -> u32 {
+        self.m.max_frame_depth()
+    }
+
+    /// Largest memory size in bytes seen so far.
+    pub fn max_mem(&self) -> u32 {
+        self.m.max_mem()
+    }
+
+    /// Give up the session and take the machine back, raw pointers and all.
+    pub fn into_machine(self) -> Machine {
+        self.m
+    }
+
+    fn value(&self, x: XData) -> Result<Value, InsnException> {
+        Ok(match x {
+            XData::BuiltinCode(n) => Value::BuiltinCode(n),
+            XData::Code(p) => Value::Code(p),
+            XData::I32(n) => Value::I32(n),
+            XData::Object(obj) => Value::Object(self.items(obj)?),
+        })
+    }
+
+    fn items(&self, obj: ObjPtr)
+        -> Result<Vec<(ItemId, Value)>, InsnException>
+    {
+        obj.iter_items(&self.m.mem).map(|item| {
+            let (p, _, id) = item?;
+            Ok((id, self.value(self.m.try_item_value(p)?)?))
+        }).collect()
+    }
+}
@@ -0,0 +1,117 @@
+//! Structured execution events, handed to the sink set with
+//! `Machine::on_trace`.
+//!
+//! Each step opens with a `Step` event carrying `pc`, `opcode`, and `fp`; the
+//! events it caused follow, tagged with the same step number so a sink can
+//! group them the way it would a span's children. The library never prints
+//! them itself; `Display` gives one `key=value` line per event for sinks that
+//! just want a log.
+
+use std::fmt;
+
+use crate::{Fault, InsnException, Insn, Machine, ObjPtr, Type, XData};
+
+pub(crate) type TraceHook = Box<dyn FnMut(&TraceEvent)>;
+
+#[derive(Clone, Copy, Debug)]
+pub enum TraceEvent {
+    /// An instruction is about to run. `opcode` is `None` if the fetch
+    /// failed.
+    Step { step: u64, pc: u32, opcode: Option<u32>, fp: ObjPtr },
+    Exception { step: u64, pc: u32, kind: InsnException },
+    FramePushed { step: u64, fp: ObjPtr, depth: u32 },
+    FramePopped { step: u64, fp: ObjPtr, depth: u32 },
+    ItemDefined { step: u64, id: u32, ty: Type },
+    Halted { step: u64, x: XData },
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TraceEvent::*;
+        match *self {
+            Step { step, pc, opcode, fp } => {
+                write!(f, "lob step={} pc={:#x} opcode=", step, pc)?;
+                match opcode {
+                    Some(op) => write!(f, "{}", op)?,
+                    None => f.write_str("none")?,
+                }
+                write!(f, " fp={:#x}", fp.addr())
+            },
+            Exception { step, pc, kind } => {
+                write!(f, "lob step={} event=exception pc={:#x} kind={:?}",
+                    step, pc, kind)
+            },
+            FramePushed { step, fp, depth } => {
+                write!(f, "lob step={} event=frame_pushed fp={:#x} depth={}",
+                    step, fp.addr(), depth)
+            },
+            FramePopped { step, fp, depth } => {
+                write!(f, "lob step={} event=frame_popped fp={:#x} depth={}",
+                    step, fp.addr(), depth)
+            },
+            ItemDefined { step, id, ty } => {
+                write!(f, "lob step={} event=item_defined id={:#x} type={:?}",
+                    step, id, ty)
+            },
+            Halted { step, x } => {
+                write!(f, "lob step={} event=halted x={:?}", step, x)
+            },
+        }
+    }
+}
+
+/// What a step started from, captured before it runs.
+pub(crate) struct Before {
+    step: u64,
+    pc: u32,
+    fp: ObjPtr,
+    depth: u32,
+    x: XData,
+    insn: Option<u32>,
+}
+
+impl Before {
+    pub(crate) fn capture(m: &Machine) -> Self {
+        Before {
+            step: m.steps,
+            pc: m.pc,
+            fp: m.fp,
+            depth: m.depth,
+            x: m.x,
+            insn: m.code_mem().try_load_u32(m.pc).ok(),
+        }
+    }
+}
+
+/// Pass what the step from `b` did to `sink`.
+pub(crate) fn step(
+    m: &Machine,
+    b: Before,
+    r: &Result<Option<XData>, Fault>,
+    sink: &mut TraceHook,
+) {
+    let step = b.step;
+    sink(&TraceEvent::Step {
+        step, pc: b.pc, opcode: b.insn.map(|i| i >> 29), fp: b.fp,
+    });
+    let r = match r {
+        Ok(r) => *r,
+        Err(Fault { pc, kind }) => {
+            sink(&TraceEvent::Exception { step, pc: *pc, kind: *kind });
+            return;
+        },
+    };
+    if m.depth > b.depth {
+        sink(&TraceEvent::FramePushed { step, fp: m.fp, depth: m.depth });
+    } else if m.depth < b.depth {
+        sink(&TraceEvent::FramePopped { step, fp: m.fp, depth: m.depth });
+    }
+    if let Some(Insn::Def(id)) = b.insn.map(Insn::from_u32) {
+        if id != 0 {
+            sink(&TraceEvent::ItemDefined { step, id, ty: b.x.ty() });
+        }
+    }
+    if let Some(x) = r {
+        sink(&TraceEvent::Halted { step, x });
+    }
+}
@@ -0,0 +1,48 @@
+//! Checking a whole program before running it.
+
+use core::fmt;
+
+use crate::format::friendly_hex_u32;
+use crate::{Inherent, Insn};
+
+/// Why `verify` rejected a program, and where.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VerifyError {
+    /// Byte address of the offending instruction.
+    pub addr: u32,
+    pub why: &'static str,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}: {}", friendly_hex_u32(self.addr), self.why)
+    }
+}
+
+/// Decode every word of `code` and check each instruction on its own: its
+/// immediate has to pass `Insn::validate`, an `Inh` has to name an
+/// inherent that exists, and a `Jump`, conditional or not, has to land on
+/// an instruction inside `code`. This says nothing
+/// about what the program does with its data, only that every instruction
+/// it can reach decodes cleanly.
+pub fn verify(code: &[u32]) -> Result<(), VerifyError> {
+    let len = 4*code.len() as u64;
+    for (i, &word) in code.iter().enumerate() {
+        let addr = 4*i as u32;
+        let err = |why| VerifyError { addr, why };
+        let insn = Insn::from_u32(word);
+        insn.validate().map_err(err)?;
+        if let Insn::Inh(Inherent::Unknown(_)) = insn {
+            return Err(err("unknown inherent"));
+        }
+        if let Some(target) = insn.jump_target(addr) {
+            if target & 0x3 != 0 {
+                return Err(err("jump target isn't word-aligned"));
+            }
+            if target as u64 >= len {
+                return Err(err("jump target is past the end of the code"));
+            }
+        }
+    }
+    Ok(())
+}
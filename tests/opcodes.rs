@@ -0,0 +1,97 @@
+//! Opcode-level tests: assemble a small program, run it to completion, and
+//! look at what it left in the frame it halted in.
+
+use lob::{assemble, ItemId, Machine, XData};
+
+fn run(src: &str) -> Machine {
+    let code = assemble(src).unwrap();
+    let mut m = Machine::new(&code);
+    m.run(1000).unwrap();
+    m
+}
+
+fn i32_of(m: &Machine, id: u32) -> Option<i32> {
+    m.value_of(ItemId::new(id).unwrap())?.as_i32()
+}
+
+#[test]
+fn def_adds_items() {
+    let m = run("
+        xlo 5
+        def 1
+        xlo 6
+        def 2
+        def 0
+    ");
+    assert_eq!(m.item_count_in_frame(), 2);
+    assert_eq!(m.frame_size(), 16);
+    assert_eq!(i32_of(&m, 1), Some(5));
+    assert_eq!(i32_of(&m, 2), Some(6));
+    assert_eq!(i32_of(&m, 3), None);
+}
+
+#[test]
+fn set_replaces_value() {
+    let m = run("
+        xlo 5
+        def 1
+        xlo 9
+        set 1
+        def 0
+    ");
+    assert_eq!(m.item_count_in_frame(), 1);
+    assert_eq!(i32_of(&m, 1), Some(9));
+}
+
+#[test]
+fn arithmetic() {
+    let m = run("
+        xlo 7
+        def 1
+        xlo 3
+        inh sub
+        def 2
+        xlo 6
+        def 3
+        ldi 0xFFFF_FFFC
+        inh mul
+        def 4
+        def 0
+    ");
+    // Each inherent consumes its left operand.
+    assert_eq!(m.item_count_in_frame(), 2);
+    assert_eq!(i32_of(&m, 2), Some(4));
+    assert_eq!(i32_of(&m, 4), Some(-24));
+}
+
+#[test]
+fn named_object() {
+    let m = run("
+        xlo 0
+        push 1
+        xlo 5
+        def 2
+        inh pop
+        def 0
+    ");
+    assert_eq!(m.item_count_in_frame(), 1);
+    let obj = m.value_of(ItemId::new(1).unwrap()).unwrap();
+    assert!(matches!(obj, XData::Object(_)));
+    // The object's items aren't visible from its parent.
+    assert_eq!(i32_of(&m, 2), None);
+}
+
+#[test]
+fn push_frame_shadows() {
+    let m = run("
+        xlo 5
+        def 1
+        xlo 0
+        push 0
+        xlo 6
+        def 1
+        def 0
+    ");
+    assert_eq!(m.item_count_in_frame(), 1);
+    assert_eq!(i32_of(&m, 1), Some(6));
+}